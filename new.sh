@@ -49,3 +49,7 @@ file = "${ISOIMG}"
 media = "cdrom"
 EOF
 fi
+
+if [ $# -eq 1 ]; then
+    echo "Create the disk image with: vm-disk create ${VMNAME} main <size>"
+fi
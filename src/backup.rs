@@ -0,0 +1,227 @@
+use std::{fs, path};
+use crate::vm::{self, modules::storage::StorageModule};
+use crate::disk::{attached_node, close_target, create_args, image_args, named_node, node_size, open_target, qemu_img_for};
+
+// Dirty bitmap tracking the writes since the last backup.
+const BITMAP: &str = "vmman-backup";
+const MANIFEST: &str = "manifest";
+
+fn usage() -> ! {
+    println!("Usage: vm-backup <vm> <destination dir>");
+    std::process::exit(1);
+}
+
+// Backups of encrypted disks are encrypted with the key of the disk.
+fn create_target(storage: &StorageModule, target: &path::Path, backing: Option<&str>, size: &str) {
+    let mut args = vec![String::from("create")];
+    args.extend(create_args(storage, "-f", "qcow2"));
+    if let Some(b) = backing {
+	args.extend([String::from("-b"), String::from(b), String::from("-F"), String::from("qcow2")]);
+	// The encrypted backing file cannot be opened without its key, and
+	// the size is given anyway.
+	if storage.is_encrypted() {
+	    args.push(String::from("-u"));
+	}
+    }
+    args.push(String::from(target.to_str().unwrap()));
+    args.push(String::from(size));
+    qemu_img_for(storage, &args.iter().map(|a| a.as_str()).collect::<Vec<_>>());
+}
+
+// One line per disk and backup, oldest first:
+//   <tag> <section> full|incremental <file>
+struct Entry {
+    tag: String,
+    section: String,
+    kind: String,
+    file: String,
+}
+
+fn read_manifest(dir: &path::Path) -> Vec<Entry> {
+    let content = fs::read_to_string(dir.join(MANIFEST)).unwrap_or_default();
+    content.lines()
+	.filter_map(|l| {
+	    let f: Vec<&str> = l.split_whitespace().collect();
+	    match f.as_slice() {
+		[tag, section, kind, file] => Some(Entry {
+		    tag: String::from(*tag), section: String::from(*section),
+		    kind: String::from(*kind), file: String::from(*file),
+		}),
+		_ => None,
+	    }
+	})
+	.collect()
+}
+
+fn append_manifest(dir: &path::Path, entries: &[Entry]) {
+    let mut content = fs::read_to_string(dir.join(MANIFEST)).unwrap_or_default();
+    for e in entries {
+	content += &format!("{} {} {} {}\n", e.tag, e.section, e.kind, e.file);
+    }
+    fs::write(dir.join(MANIFEST), content).expect("Cannot write the backup manifest");
+}
+
+fn timestamp() -> String {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+	let t = libc::time(std::ptr::null_mut());
+	libc::localtime_r(&t, &mut tm);
+    }
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday,
+	    tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+fn backup_disks(conf: &vm::VmConf) -> Vec<&StorageModule> {
+    conf.modules.iter()
+	.filter_map(|m| m.as_storage())
+	.filter(|s| !s.is_cdrom())
+	.collect()
+}
+
+// A stopped VM cannot change its disks, so a plain copy is a consistent
+// full backup. Bitmaps left by a running VM stay valid for the next run,
+// they only track more than needed.
+fn offline(conf: &vm::VmConf, dir: &path::Path, tag: &str) -> Vec<Entry> {
+    backup_disks(conf).iter().map(|storage| {
+	let file = format!("{}-{}.qcow2", storage.name, tag);
+	let (image, format) = storage.active_image();
+	let mut args = vec![String::from("convert"), String::from("-p")];
+	args.extend(image_args(storage, &image, &format));
+	args.extend(create_args(storage, "-O", "qcow2"));
+	args.push(String::from(dir.join(&file).to_str().unwrap()));
+	qemu_img_for(storage, &args.iter().map(|a| a.as_str()).collect::<Vec<_>>());
+	Entry { tag: String::from(tag), section: storage.name.clone(), kind: String::from("full"), file }
+    }).collect()
+}
+
+// All disks are backed up by one transaction, so they are consistent with
+// each other. Each backup is a qcow2 file, incremental ones have the
+// previous backup of the disk as their backing file.
+fn online(conf: &vm::VmConf, dir: &path::Path, tag: &str, manifest: &[Entry]) -> Vec<Entry> {
+    let mut qmp = conf.qmp();
+    let disks = backup_disks(conf);
+    let mut actions = json::JsonValue::new_array();
+    let mut entries = Vec::new();
+    let mut new_bitmaps = Vec::new();
+    let mut jobs = Vec::new();
+    for storage in disks.iter() {
+	let node = attached_node(&mut qmp, storage);
+	let info = named_node(&mut qmp, &node);
+	let has_bitmap = info["dirty-bitmaps"].members().any(|b| b["name"] == BITMAP);
+	let prev = manifest.iter().rev().find(|e| e.section == storage.name);
+	let file = format!("{}-{}.qcow2", storage.name, tag);
+	let target = dir.join(&file);
+	let size = node_size(&mut qmp, &node).to_string();
+	let job = format!("backup-{}", storage.name);
+
+	let kind = match prev {
+	    Some(prev) if has_bitmap => {
+		// A relative backing file keeps the backup dir movable.
+		create_target(storage, &target, Some(&prev.file), &size);
+		"incremental"
+	    }
+	    _ => {
+		create_target(storage, &target, None, &size);
+		if has_bitmap {
+		    actions.push(json::object!{
+			"type": "block-dirty-bitmap-clear",
+			"data": { "node": node.as_str(), "name": BITMAP },
+		    }).unwrap();
+		} else {
+		    // Only qcow2 images can keep the bitmap across restarts of
+		    // qemu, other disks get a full backup after each restart.
+		    let persistent = info["drv"] == "qcow2";
+		    actions.push(json::object!{
+			"type": "block-dirty-bitmap-add",
+			"data": { "node": node.as_str(), "name": BITMAP, "persistent": persistent },
+		    }).unwrap();
+		}
+		new_bitmaps.push(node.clone());
+		"full"
+	    }
+	};
+	let data = json::object!{
+	    "job-id": job.as_str(),
+	    "device": node.as_str(),
+	    "sync": kind,
+	};
+	jobs.push((target, data));
+	entries.push(Entry { tag: String::from(tag), section: storage.name.clone(), kind: String::from(kind), file });
+    }
+
+    // The targets are opened once all of them exist. If one cannot be
+    // opened, the ones before it are closed again, so that no target node
+    // stays in qemu and fails the next backup.
+    for (i, (storage, (target, mut data))) in disks.iter().zip(jobs).enumerate() {
+	match open_target(&mut qmp, storage, target.to_str().unwrap(), "qcow2") {
+	    Ok(target_node) => data["target"] = target_node.into(),
+	    Err(e) => {
+		for s in disks[..i].iter() {
+		    close_target(&mut qmp, s);
+		}
+		for e in entries.iter() {
+		    let _ = fs::remove_file(dir.join(&e.file));
+		}
+		panic!("Cannot open the backup target {}: {}", target.display(), e);
+	    }
+	}
+	if data["sync"] == "incremental" {
+	    data["bitmap"] = BITMAP.into();
+	}
+	actions.push(json::object!{ "type": "blockdev-backup", "data": data }).unwrap();
+    }
+
+    let mut r = qmp.execute("transaction", json::object!{
+	"actions": actions,
+	"properties": { "completion-mode": "grouped" },
+    }).map(|_| ());
+    if r.is_ok() {
+	for storage in disks.iter() {
+	    let job = format!("backup-{}", storage.name);
+	    r = r.and(qmp.wait_job(&job));
+	}
+    }
+    for storage in disks.iter() {
+	close_target(&mut qmp, storage);
+    }
+    if let Err(e) = r {
+	// A failed incremental backup keeps its bitmap, but a fresh bitmap
+	// would make the next backup skip what the failed full one missed.
+	for node in new_bitmaps.iter() {
+	    let _ = qmp.execute("block-dirty-bitmap-remove", json::object!{ "node": node.as_str(), "name": BITMAP });
+	}
+	for e in entries.iter() {
+	    let _ = fs::remove_file(dir.join(&e.file));
+	}
+	panic!("Cannot back up {}: {}", conf.name, e);
+    }
+    entries
+}
+
+// vm-backup <vm> <destination dir>
+pub fn backup(mgr: &mut vm::VmManager, args: &[String]) {
+    let pos = vm::positional_args(args);
+    if pos.len() != 2 {
+	usage();
+    }
+    let conf = mgr.vmconfs.get_mut(&pos[0]).unwrap_or_else(|| panic!("Cannot find machine {}", pos[0]));
+    conf.load();
+    let dir = path::Path::new(&pos[1]);
+    fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Cannot create {}: {}", dir.display(), e));
+    let manifest = read_manifest(dir);
+    let tag = timestamp();
+    if manifest.iter().any(|e| e.tag == tag) {
+	panic!("Backup {} already exists", tag);
+    }
+
+    let entries = if conf.try_qmp().is_some() {
+	online(conf, dir, &tag, &manifest)
+    } else {
+	println!("{} is not running, taking a full backup", conf.name);
+	offline(conf, dir, &tag)
+    };
+    append_manifest(dir, &entries);
+    for e in entries.iter() {
+	println!("storage.{}: {} backup {}", e.section, e.kind, dir.join(&e.file).display());
+    }
+}
@@ -0,0 +1,165 @@
+use std::{collections::HashSet, fs, io::Read, path};
+use toml::{value, Value};
+use crate::vm;
+use crate::disk::{create_args, image_args, qemu_img, qemu_img_for};
+
+// Linux interface names are at most 15 bytes.
+const IFNAME_MAX: usize = 15;
+
+fn usage() -> ! {
+    println!("Usage: vm-clone <source> <destination> [--full]");
+    std::process::exit(1);
+}
+
+// Same scheme as new.sh: BE:EF followed by 4 random bytes.
+fn random_mac() -> String {
+    let mut bytes = [0u8; 4];
+    fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes))
+	.expect("Cannot read /dev/urandom");
+    format!("BE:EF:{:02X}:{:02X}:{:02X}:{:02X}", bytes[0], bytes[1], bytes[2], bytes[3])
+}
+
+// FNV-1a, cut to 4 hex digits.
+fn short_hash(s: &str) -> String {
+    let h = s.bytes().fold(0x811c9dc5u32, |h, b| (h ^ b as u32).wrapping_mul(0x01000193));
+    format!("{:04x}", h & 0xffff)
+}
+
+// $USER$DST, with a hash of it in place of its end when it is too long, so
+// that clones with long names sharing a prefix get different taps.
+fn interface_name(user: &str, dst: &str, idx: usize) -> String {
+    let suffix = if idx == 0 { String::new() } else { idx.to_string() };
+    let name = format!("{}{}", user, dst);
+    if name.len() + suffix.len() <= IFNAME_MAX {
+	return name + &suffix;
+    }
+    let hash = short_hash(&name);
+    let mut end = IFNAME_MAX - hash.len() - suffix.len();
+    while !name.is_char_boundary(end) {
+	end -= 1;
+    }
+    format!("{}{}{}", &name[..end], hash, suffix)
+}
+
+// Interfaces of all VMs of the user and of the host. vm-init deletes an
+// existing link of the same name, which could belong to another VM.
+fn used_interfaces(mgr: &vm::VmManager) -> HashSet<String> {
+    let mut used = HashSet::new();
+    for conf in mgr.vmconfs.values() {
+	let table = match fs::read_to_string(&conf.filename).ok().and_then(|c| toml::from_str::<value::Table>(&c).ok()) {
+	    Some(t) => t,
+	    None => continue,
+	};
+	for heading in ["macvtap", "tapbridge"].iter() {
+	    let sections = table.get(*heading).and_then(|s| s.as_table()).into_iter().flat_map(|s| s.values());
+	    used.extend(sections.filter_map(|s| s.get("interface")).filter_map(|i| i.as_str()).map(String::from));
+	}
+    }
+    if let Ok(entries) = fs::read_dir("/sys/class/net") {
+	used.extend(entries.filter_map(|e| e.ok()).filter_map(|e| e.file_name().into_string().ok()));
+    }
+    used
+}
+
+fn set_string(section: &mut value::Table, key: &str, v: &str) {
+    section.insert(String::from(key), Value::String(String::from(v)));
+}
+
+// vm-clone <source> <destination> [--full]
+//
+// Copies the toml of a (golden) VM with fresh network identities, and gives
+// the clone qcow2 overlays backed by the source disks, or full copies.
+pub fn clone(mgr: &mut vm::VmManager, args: &[String]) {
+    let pos = vm::positional_args(args);
+    if pos.len() != 2 {
+	usage();
+    }
+    let (src, dst) = (&pos[0], &pos[1]);
+    let full = vm::has_flag(args, "--full");
+    let dst_file = path::Path::new(&mgr.confdir).join(format!("{}.toml", dst));
+    if mgr.vmconfs.contains_key(dst) || dst_file.exists() {
+	panic!("Machine {} already exists", dst);
+    }
+    let used = used_interfaces(mgr);
+    let conf = mgr.vmconfs.get_mut(src).unwrap_or_else(|| panic!("Cannot find machine {}", src));
+    conf.load();
+    // qemu opens the backing file of an overlay from its header, without the
+    // key an encrypted backing file needs.
+    if !full {
+	if let Some(s) = conf.modules.iter().filter_map(|m| m.as_storage()).find(|s| s.is_encrypted() && !s.is_cdrom() && !s.is_network() && !s.is_block_device()) {
+	    panic!("storage.{} is encrypted and cannot back a linked clone, use --full", s.name);
+	}
+    }
+    let content = fs::read_to_string(&conf.filename).expect("Cannot read file");
+    let mut table = toml::from_str::<value::Table>(&content).expect("Cannot parse configuration");
+    table.remove("autostart");
+
+    let user = vm::user_name();
+    // Checked before any disk is created.
+    let nics = ["macvtap", "tapbridge"].iter()
+	.filter_map(|h| table.get(*h).and_then(|s| s.as_table()))
+	.map(|s| s.len())
+	.sum::<usize>();
+    if let Some(ifname) = (0..nics).map(|i| interface_name(&user, dst, i)).find(|n| used.contains(n)) {
+	panic!("Interface {} is already in use, pick another name for the clone", ifname);
+    }
+    let mut nic_idx = 0;
+    for (module_name, sections) in table.iter_mut() {
+	let sections = match sections.as_table_mut() {
+	    Some(s) => s,
+	    None => continue,
+	};
+	for (section_name, section) in sections.iter_mut() {
+	    let section = section.as_table_mut().expect("Section must have names");
+	    match module_name.as_str() {
+		"macvtap" | "tapbridge" => {
+		    let ifname = interface_name(&user, dst, nic_idx);
+		    nic_idx += 1;
+		    println!("{}.{}: interface {}", module_name, section_name, ifname);
+		    set_string(section, "interface", &ifname);
+		    set_string(section, "mac", &random_mac());
+		}
+		"storage" => {
+		    let storage = conf.storage(section_name);
+		    if storage.is_cdrom() {
+			continue;
+		    }
+		    if storage.is_network() || storage.is_block_device() {
+			println!("storage.{}: {} is shared with {}", section_name, storage.filename, src);
+			continue;
+		    }
+		    let (image, format) = storage.active_image();
+		    let image = fs::canonicalize(&image).unwrap_or_else(|e| panic!("Cannot find image {}: {}", image, e));
+		    let dir = image.parent().expect("Cannot find image dir");
+		    let new_format = if full { format.clone() } else { String::from("qcow2") };
+		    let ext = if new_format == "raw" { "img" } else { new_format.as_str() };
+		    let new_image = dir.join(format!("{}-{}.{}", dst, section_name, ext));
+		    if new_image.exists() {
+			panic!("{} already exists", new_image.display());
+		    }
+		    let (image, new_image) = (image.to_str().unwrap(), new_image.to_str().unwrap());
+		    if full {
+			// Copies of encrypted disks keep the encryption and the key.
+			let mut args = vec![String::from("convert"), String::from("-p")];
+			args.extend(image_args(storage, image, &format));
+			args.extend(create_args(storage, "-O", &new_format));
+			args.push(String::from(new_image));
+			qemu_img_for(storage, &args.iter().map(|a| a.as_str()).collect::<Vec<_>>());
+		    } else {
+			qemu_img(&["create", "-f", "qcow2", "-b", image, "-F", &format, new_image]);
+		    }
+		    set_string(section, "file", new_image);
+		    set_string(section, "format", &new_format);
+		}
+		_ => {}
+	    }
+	}
+    }
+
+    fs::write(&dst_file, toml::to_string(&Value::Table(table)).expect("Cannot serialize configuration"))
+	.unwrap_or_else(|e| panic!("Cannot write {}: {}", dst_file.display(), e));
+    println!("Cloned {} into {}", src, dst_file.display());
+    if !full {
+	println!("{} must not be modified while linked clones use its disks", src);
+    }
+}
@@ -0,0 +1,73 @@
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::{io, process, thread};
+use crate::vm;
+
+// Ctrl-]
+const ESCAPE: u8 = 0x1d;
+
+fn set_termios(t: &libc::termios) {
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, t) };
+}
+
+// Puts the terminal into raw mode, returning the settings to restore.
+fn enter_raw_mode() -> Option<libc::termios> {
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } != 0 {
+	return None;
+    }
+    let mut raw = saved;
+    unsafe { libc::cfmakeraw(&mut raw) };
+    set_termios(&raw);
+    Some(saved)
+}
+
+pub fn attach(conf: &vm::VmConf) {
+    let sock = conf.console_socket();
+    let mut stream = UnixStream::connect(&sock)
+	.unwrap_or_else(|e| panic!("Cannot connect to the console of {} at {}: {}", conf.name, sock.display(), e));
+    let mut reader = stream.try_clone().expect("Cannot clone console socket");
+    println!("Connected to {}, escape character is ^]", conf.name);
+
+    let saved = enter_raw_mode();
+    thread::spawn(move || {
+	let mut buf = [0u8; 4096];
+	let mut stdout = io::stdout();
+	loop {
+	    match reader.read(&mut buf) {
+		Ok(0) | Err(_) => break,
+		Ok(n) => {
+		    if stdout.write_all(&buf[..n]).and_then(|_| stdout.flush()).is_err() {
+			break;
+		    }
+		}
+	    }
+	}
+	if let Some(t) = saved.as_ref() {
+	    set_termios(t);
+	}
+	println!("\nConsole closed");
+	process::exit(0);
+    });
+
+    let mut buf = [0u8; 1024];
+    let mut stdin = io::stdin();
+    loop {
+	let n = match stdin.read(&mut buf) {
+	    Ok(0) | Err(_) => break,
+	    Ok(n) => n,
+	};
+	let input = &buf[..n];
+	let (input, detach) = match input.iter().position(|c| *c == ESCAPE) {
+	    Some(idx) => (&input[..idx], true),
+	    None => (input, false),
+	};
+	if stream.write_all(input).is_err() || detach {
+	    break;
+	}
+    }
+    if let Some(t) = saved.as_ref() {
+	set_termios(t);
+    }
+    println!("\nDetached from {}", conf.name);
+}
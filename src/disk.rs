@@ -0,0 +1,353 @@
+use std::process::Command;
+use crate::vm::{self, image, modules::{secret, storage::{Props, StorageModule}}, qmp::Qmp};
+
+fn usage() -> ! {
+    println!("Usage: vm-disk create <vm> <section> <size> [--format=FORMAT]");
+    println!("       vm-disk resize <vm> <section> [+-]<size>");
+    println!("       vm-disk info <vm> <section>");
+    println!("       vm-disk convert <vm> <section> <destination> [--format=FORMAT]");
+    println!("       vm-disk commit|discard <vm> <section>");
+    std::process::exit(1);
+}
+
+// Sizes in bytes, with optional K, M, G or T (binary) suffixes.
+pub fn parse_size(s: &str) -> u64 {
+    let err = format!("Invalid size {}", s);
+    let (num, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+	Some('K') => (&s[..s.len() - 1], 10),
+	Some('M') => (&s[..s.len() - 1], 20),
+	Some('G') => (&s[..s.len() - 1], 30),
+	Some('T') => (&s[..s.len() - 1], 40),
+	_ => (s, 0),
+    };
+    num.parse::<u64>().expect(&err).checked_mul(1 << shift).expect(&err)
+}
+
+pub fn qemu_img(args: &[&str]) {
+    println!("qemu-img {}", args.join(" "));
+    let status = Command::new("qemu-img").args(args).status().expect("Cannot run qemu-img");
+    if !status.success() {
+	panic!("qemu-img {} failed: {}", args[0], status);
+    }
+}
+
+// Runs qemu-img with the key of an encrypted disk, which reaches qemu-img
+// through a pipe like it reaches qemu, as the secret of the disk.
+pub fn qemu_img_key(storage: &StorageModule, key: &[u8], args: &[&str]) {
+    let fd = secret::key_fd(key);
+    let object = format!("secret,id={},file=/dev/fd/{},format=raw", storage.secret_id(), fd);
+    let mut full = vec![args[0], "--object", &object];
+    full.extend_from_slice(&args[1..]);
+    qemu_img(&full);
+    secret::close_fd(fd);
+}
+
+// qemu_img() on images of the disk, with its key if it is encrypted.
+pub fn qemu_img_for(storage: &StorageModule, args: &[&str]) {
+    if storage.is_encrypted() {
+	qemu_img_key(storage, &storage.read_key(false), args);
+    } else {
+	qemu_img(args);
+    }
+}
+
+// `-f` or `-O` arguments for a new image of the disk in `format`, which is
+// encrypted with the key of the disk if the disk is.
+pub fn create_args(storage: &StorageModule, flag: &str, format: &str) -> Vec<String> {
+    let mut args = vec![String::from(flag), storage.image_driver(format)];
+    let opts = storage.key_props(Props::new(), format).to_arg();
+    if !opts.is_empty() {
+	args.push(String::from("-o"));
+	args.push(opts);
+    }
+    args
+}
+
+// Arguments naming an image of the disk as the source of qemu-img.
+pub fn image_args(storage: &StorageModule, filename: &str, format: &str) -> Vec<String> {
+    if !storage.is_encrypted() {
+	return vec![String::from("-f"), String::from(format), String::from(filename)];
+    }
+    let props = Props::new()
+	.str("driver", &storage.image_driver(format))
+	.str("file.filename", filename);
+    vec![String::from("--image-opts"), storage.key_props(props, format).to_arg()]
+}
+
+// Leases of the disks of a stopped VM while their images are changed, so
+// that a VM running on another host is not corrupted. They are given back
+// when dropped, also when the change fails.
+pub struct Leases<'a>(Vec<&'a StorageModule>);
+
+impl<'a> Leases<'a> {
+    pub fn acquire(disks: &[&'a StorageModule]) -> Leases<'a> {
+	let mut leases = Leases(Vec::new());
+	for d in disks {
+	    d.acquire_lease(std::process::id());
+	    leases.0.push(d);
+	}
+	leases
+    }
+}
+
+impl Drop for Leases<'_> {
+    fn drop(&mut self) {
+	for d in self.0.iter() {
+	    d.release_lease();
+	}
+    }
+}
+
+// The node the guest device is attached to, which moves up when snapshots
+// are taken while the VM runs.
+pub fn attached_node(qmp: &mut Qmp, storage: &StorageModule) -> String {
+    let blocks = qmp.execute("query-block", json::JsonValue::new_object()).expect("Cannot query block devices");
+    let id = storage.device_id();
+    let prefix = format!("/machine/peripheral/{}/", id);
+    blocks.members()
+	.find(|b| b["qdev"] == id.as_str() || b["qdev"].as_str().is_some_and(|q| q.starts_with(&prefix)))
+	.and_then(|b| b["inserted"]["node-name"].as_str().map(String::from))
+	.unwrap_or(storage.top_node())
+}
+
+pub fn named_node(qmp: &mut Qmp, node: &str) -> json::JsonValue {
+    let nodes = qmp.execute("query-named-block-nodes", json::object!{ "flat": true })
+	.expect("Cannot query block nodes");
+    let n = nodes.members().find(|n| n["node-name"] == node)
+	.unwrap_or_else(|| panic!("Cannot find block node {}", node));
+    n.clone()
+}
+
+// Image size as qemu sees it in the running VM.
+pub fn node_size(qmp: &mut Qmp, node: &str) -> u64 {
+    named_node(qmp, node)["image"]["virtual-size"].as_u64().expect("Cannot get the image size")
+}
+
+fn create(storage: &StorageModule, size: &str, format: Option<String>) {
+    if storage.is_network() || storage.is_block_device() {
+	panic!("storage.{} is not an image file", storage.name);
+    }
+    if std::path::Path::new(&storage.filename).exists() {
+	panic!("{} already exists", storage.filename);
+    }
+    let format = storage.format.clone().or(format).unwrap_or(String::from("raw"));
+    let size = parse_size(size).to_string();
+    let mut args = vec![String::from("create")];
+    args.extend(create_args(storage, "-f", &format));
+    args.push(storage.filename.clone());
+    args.push(size);
+    let args = args.iter().map(|a| a.as_str()).collect::<Vec<_>>();
+    if storage.is_encrypted() {
+	qemu_img_key(storage, &storage.read_key(true), &args);
+    } else {
+	qemu_img(&args);
+    }
+}
+
+fn resize(conf: &vm::VmConf, storage: &StorageModule, size: &str) {
+    match conf.try_qmp() {
+	Some(mut qmp) => {
+	    let node = attached_node(&mut qmp, storage);
+	    let current = node_size(&mut qmp, &node);
+	    let new_size = match (size.strip_prefix('+'), size.strip_prefix('-')) {
+		(Some(s), _) => current.checked_add(parse_size(s)).unwrap_or_else(|| panic!("Invalid size {}", size)),
+		(_, Some(s)) => current.checked_sub(parse_size(s)).expect("Cannot shrink below zero"),
+		_ => parse_size(size),
+	    };
+	    qmp.execute("block_resize", json::object!{ "node-name": node.as_str(), "size": new_size })
+		.expect("Cannot resize the disk");
+	    println!("Resized storage.{} of running {} from {} to {} bytes", storage.name, conf.name, current, new_size);
+	}
+	None => {
+	    let _leases = Leases::acquire(&[storage]);
+	    let (filename, format) = storage.active_image();
+	    let arg = match (size.strip_prefix('+'), size.strip_prefix('-')) {
+		(Some(s), _) => format!("+{}", parse_size(s)),
+		(_, Some(s)) => format!("-{}", parse_size(s)),
+		_ => parse_size(size).to_string(),
+	    };
+	    let mut args = vec![String::from("resize")];
+	    if arg.starts_with('-') {
+		args.push(String::from("--shrink"));
+	    }
+	    args.extend(image_args(storage, &filename, &format));
+	    args.push(arg);
+	    qemu_img_for(storage, &args.iter().map(|a| a.as_str()).collect::<Vec<_>>());
+	}
+    }
+}
+
+fn info(conf: &vm::VmConf, storage: &StorageModule) {
+    let (filename, format) = storage.active_image();
+    let mut args = vec!["info", "-f", &format, "--backing-chain"];
+    // qemu holds the image locks while running.
+    if conf.try_qmp().is_some() {
+	args.push("-U");
+    }
+    args.push(&filename);
+    qemu_img(&args);
+}
+
+// Opens an image file in the running VM, to be the target of a backup job.
+// Targets of encrypted disks use the secret of the disk.
+pub fn open_target(qmp: &mut Qmp, storage: &StorageModule, target: &str, format: &str) -> Result<String, String> {
+    let target_node = format!("target-{}", storage.name);
+    let target_file = format!("target-file-{}", storage.name);
+    qmp.execute("blockdev-add", json::object!{ "driver": "file", "node-name": target_file.as_str(), "filename": target })?;
+    let props = Props::new()
+	.str("driver", &storage.image_driver(format))
+	.str("node-name", &target_node)
+	.str("file", &target_file);
+    let mut args = storage.key_props(props, format).to_json();
+    // The job only writes to the target, so the backing files of an
+    // incremental target, which would need their keys, stay closed.
+    if format == "qcow2" {
+	args["backing"] = json::JsonValue::Null;
+    }
+    if let Err(e) = qmp.execute("blockdev-add", args) {
+	let _ = qmp.execute("blockdev-del", json::object!{ "node-name": target_file.as_str() });
+	return Err(e);
+    }
+    Ok(target_node)
+}
+
+pub fn close_target(qmp: &mut Qmp, storage: &StorageModule) {
+    let _ = qmp.execute("blockdev-del", json::object!{ "node-name": format!("target-{}", storage.name) });
+    let _ = qmp.execute("blockdev-del", json::object!{ "node-name": format!("target-file-{}", storage.name) });
+}
+
+// Copies the image of a running VM consistently with a full backup job.
+pub fn backup_to(qmp: &mut Qmp, storage: &StorageModule, target: &str, format: &str) {
+    let node = attached_node(qmp, storage);
+    let size = node_size(qmp, &node).to_string();
+    let mut args = vec![String::from("create")];
+    args.extend(create_args(storage, "-f", format));
+    args.push(String::from(target));
+    args.push(size);
+    qemu_img_for(storage, &args.iter().map(|a| a.as_str()).collect::<Vec<_>>());
+    let target_node = open_target(qmp, storage, target, format)
+	.unwrap_or_else(|e| panic!("Cannot open the target image: {}", e));
+
+    let job = format!("backup-{}", storage.name);
+    let args = json::object!{
+	"job-id": job.as_str(),
+	"device": node.as_str(),
+	"target": target_node.as_str(),
+	"sync": "full",
+    };
+    let r = qmp.execute("blockdev-backup", args).and_then(|_| qmp.wait_job(&job));
+    close_target(qmp, storage);
+    if let Err(e) = r {
+	panic!("Cannot copy storage.{}: {}", storage.name, e);
+    }
+}
+
+fn convert(conf: &vm::VmConf, storage: &StorageModule, dst: &str, format: Option<String>) {
+    let dst_format = format.unwrap_or(String::from("qcow2"));
+    if !image::FORMATS.contains(&dst_format.as_str()) {
+	panic!("Unsupported format {}", dst_format);
+    }
+    if std::path::Path::new(dst).exists() {
+	panic!("{} already exists", dst);
+    }
+    match conf.try_qmp() {
+	Some(mut qmp) => {
+	    println!("{} is running, copying storage.{} with a backup job", conf.name, storage.name);
+	    backup_to(&mut qmp, storage, dst, &dst_format);
+	}
+	None => {
+	    // Copies of encrypted disks are encrypted with the same key.
+	    let (filename, format) = storage.active_image();
+	    let mut args = vec![String::from("convert"), String::from("-p")];
+	    args.extend(image_args(storage, &filename, &format));
+	    args.extend(create_args(storage, "-O", &dst_format));
+	    args.push(String::from(dst));
+	    qemu_img_for(storage, &args.iter().map(|a| a.as_str()).collect::<Vec<_>>());
+	}
+    }
+}
+
+fn local_cache<'a>(conf: &vm::VmConf, storage: &'a StorageModule) -> &'a std::path::Path {
+    if conf.try_qmp().is_some() {
+	panic!("{} is running, stop it first", conf.name);
+    }
+    let overlay = storage.local_cache.as_ref().unwrap_or_else(|| panic!("storage.{} has no local cache", storage.name));
+    if !overlay.exists() {
+	panic!("The local cache {} is empty", overlay.display());
+    }
+    overlay
+}
+
+// Writes the changes kept in the local cache back to the shared image. The
+// cache stays warm, as it now matches the image.
+fn commit(conf: &vm::VmConf, storage: &StorageModule) {
+    let overlay = local_cache(conf, storage);
+    let _leases = Leases::acquire(&[storage]);
+    qemu_img(&["commit", "-d", "-f", "qcow2", overlay.to_str().unwrap()]);
+}
+
+fn discard(conf: &vm::VmConf, storage: &StorageModule) {
+    let overlay = local_cache(conf, storage);
+    std::fs::remove_file(overlay).unwrap_or_else(|e| panic!("Cannot remove {}: {}", overlay.display(), e));
+    println!("Discarded the changes of storage.{} in {}", storage.name, overlay.display());
+}
+
+// vm-disk create|resize|info|convert|commit|discard <vm> <section> ...
+pub fn disk(mgr: &mut vm::VmManager, args: &[String]) {
+    let pos = vm::positional_args(args);
+    if pos.len() < 3 {
+	usage();
+    }
+    let conf = mgr.vmconfs.get_mut(&pos[1]).unwrap_or_else(|| panic!("Cannot find machine {}", pos[1]));
+    conf.force_unlock = vm::has_flag(args, "--force-unlock");
+    conf.load();
+    let storage = conf.storage(&pos[2]);
+    let format = vm::flag_value(args, "--format");
+    match (pos[0].as_str(), pos.get(3)) {
+	("create", Some(size)) => create(storage, size, format),
+	("resize", Some(size)) => resize(conf, storage, size),
+	("info", None) => info(conf, storage),
+	("convert", Some(dst)) => convert(conf, storage, dst, format),
+	("commit", None) => commit(conf, storage),
+	("discard", None) => discard(conf, storage),
+	_ => usage(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_size;
+
+    #[test]
+    fn parse_size_suffixes() {
+	assert_eq!(parse_size("512"), 512);
+	assert_eq!(parse_size("4K"), 4 << 10);
+	assert_eq!(parse_size("10m"), 10 << 20);
+	assert_eq!(parse_size("20G"), 20 << 30);
+	assert_eq!(parse_size("1T"), 1 << 40);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid size")]
+    fn parse_size_invalid() {
+	parse_size("10GB");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid size")]
+    fn parse_size_empty() {
+	parse_size("G");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid size")]
+    fn parse_size_negative() {
+	parse_size("-1G");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid size")]
+    fn parse_size_overflow() {
+	parse_size("99999999999T");
+    }
+}
@@ -0,0 +1,288 @@
+use std::{fs, path, time};
+use std::os::unix::io::AsRawFd;
+use std::process::Command;
+use toml::{value, Value};
+use crate::vm::{self, modules::{storage::StorageModule, ConfModule}, qmp::Qmp};
+
+// How long the guest gets to let go of an unplugged device.
+const UNPLUG_TIMEOUT: u64 = 30;
+
+fn usage() -> ! {
+    println!("Usage: vm-attach-disk <vm> <file> [--bus=BUS] [--format=FORMAT] [--name=SECTION] [--persist]");
+    println!("       vm-detach-disk <vm> <section> [--persist]");
+    println!("       vm-attach-nic <vm> <section>");
+    println!("       vm-detach-nic <vm> <section>");
+    std::process::exit(1);
+}
+
+// A free root port created by `hotplug-slots` in the base section, if any.
+pub fn free_hotplug_port(qmp: &mut Qmp) -> Option<String> {
+    let buses = qmp.execute("query-pci", json::JsonValue::new_object()).expect("Cannot query PCI devices");
+    buses.members()
+	.flat_map(|b| b["devices"].members())
+	.filter(|d| d["qdev_id"].as_str().is_some_and(|id| id.starts_with("hotplug")))
+	.find(|d| d["pci_bridge"]["devices"].is_empty())
+	.and_then(|d| d["qdev_id"].as_str().map(String::from))
+}
+
+// Sections added at runtime without --persist are remembered in the
+// runtime dir, so that they can be detached again.
+fn hotplug_record(conf: &vm::VmConf, section: &str) -> path::PathBuf {
+    conf.runtime_dir().join(format!("hotplug-{}.toml", section))
+}
+
+fn read_record(conf: &vm::VmConf, section: &str) -> Option<value::Table> {
+    fs::read_to_string(hotplug_record(conf, section)).ok()
+	.map(|c| toml::from_str::<value::Table>(&c).expect("Cannot parse the hot-plug record"))
+}
+
+fn has_section(conf: &vm::VmConf, section: &str) -> bool {
+    conf.modules.iter().filter_map(|m| m.as_storage()).any(|s| s.name == section)
+	|| hotplug_record(conf, section).exists()
+}
+
+fn to_toml(section: &value::Table) -> String {
+    toml::to_string(&Value::Table(section.clone())).expect("Cannot serialize the section")
+}
+
+// Appends the section as text, keeping the rest of the file as written.
+fn persist_section(conf: &vm::VmConf, heading: &str, name: &str, section: &value::Table) {
+    let mut content = fs::read_to_string(&conf.filename).expect("Cannot read file");
+    if !content.is_empty() && !content.ends_with('\n') {
+	content.push('\n');
+    }
+    content += &format!("[{}.{}]\n{}", heading, name, to_toml(section));
+    fs::write(&conf.filename, content).unwrap_or_else(|e| panic!("Cannot write {}: {}", conf.filename, e));
+    println!("Added [{}.{}] to {}", heading, name, conf.filename);
+}
+
+fn unpersist_section(conf: &vm::VmConf, heading: &str, name: &str) {
+    conf.remove_section(heading, name).unwrap_or_else(|e| panic!("{}", e));
+    println!("Removed [{}.{}] from {}", heading, name, conf.filename);
+}
+
+fn attach_disk(conf: &vm::VmConf, args: &[String]) {
+    let pos = vm::positional_args(args);
+    let file = pos.get(1).unwrap_or_else(|| usage());
+    let file = fs::canonicalize(file).unwrap_or_else(|e| panic!("Cannot find {}: {}", file, e));
+    let name = vm::flag_value(args, "--name").unwrap_or_else(|| {
+	(1..).map(|i| format!("disk{}", i)).find(|n| !has_section(conf, n)).unwrap()
+    });
+    if has_section(conf, &name) {
+	panic!("{} already has storage.{}", conf.name, name);
+    }
+    let bus = vm::flag_value(args, "--bus").unwrap_or(String::from("virtio-blk"));
+    let mut section = value::Table::new();
+    section.insert(String::from("bus"), Value::String(bus));
+    section.insert(String::from("file"), Value::String(String::from(file.to_str().unwrap())));
+    if let Some(format) = vm::flag_value(args, "--format") {
+	section.insert(String::from("format"), Value::String(format));
+    }
+    let storage = StorageModule::new(&name, &section, &conf.vmenv());
+    if storage.is_block_device() {
+	panic!("Host block devices need vm-init, add them to the toml instead");
+    }
+    let (image, format) = storage.active_image();
+    vm::image::validate_chain(&image, &format).unwrap_or_else(|e| panic!("{}", e));
+    // Recorded like vm-run does for sections without a format.
+    section.entry(String::from("format")).or_insert(Value::String(format.clone()));
+
+    let mut qmp = conf.qmp();
+    storage.acquire_lease(conf.qemu_pid().unwrap_or(std::process::id()));
+    let port = free_hotplug_port(&mut qmp);
+    let mut devices = Vec::new();
+    if let Some(c) = storage.controller_props() {
+	devices.push(c);
+    }
+    devices.push(storage.device_props());
+    // Only the first device is plugged into the PCI bus, the disk itself
+    // goes onto its controller when it has one.
+    if let Some(p) = port.as_ref() {
+	devices[0] = devices[0].clone().str("bus", p);
+    }
+    let nodes = [storage.file_props(), storage.format_props(&format)];
+    let r = nodes.iter()
+	.try_for_each(|n| qmp.execute("blockdev-add", n.to_json()).map(|_| ()))
+	.and_then(|_| devices.iter().try_for_each(|d| qmp.execute("device_add", d.to_json()).map(|_| ())));
+    if let Err(e) = r {
+	let _ = qmp.execute("device_del", json::object!{ "id": storage.controller_props().map_or(storage.device_id(), |_| storage.controller_id()) });
+	for n in storage.node_names() {
+	    let _ = qmp.execute("blockdev-del", json::object!{ "node-name": n });
+	}
+	storage.release_lease();
+	if port.is_none() && e.contains("Bus") {
+	    panic!("Cannot attach {}: {}, consider adding hotplug-slots to the base section", file.display(), e);
+	}
+	panic!("Cannot attach {}: {}", file.display(), e);
+    }
+    println!("Attached {} to {} as storage.{}", file.display(), conf.name, name);
+
+    if vm::has_flag(args, "--persist") {
+	persist_section(conf, "storage", &name, &section);
+    } else {
+	fs::write(hotplug_record(conf, &name), to_toml(&section)).expect("Cannot write the hot-plug record");
+    }
+}
+
+fn detach_disk(conf: &vm::VmConf, args: &[String]) {
+    let pos = vm::positional_args(args);
+    let name = pos.get(1).unwrap_or_else(|| usage());
+    let record = read_record(conf, name);
+    let recorded;
+    let storage = match record.as_ref() {
+	Some(section) => {
+	    recorded = StorageModule::new(name, section, &conf.vmenv());
+	    &recorded
+	}
+	None => conf.storage(name),
+    };
+
+    let mut qmp = conf.qmp();
+    // Removing a controller takes the disk on it along.
+    let device = storage.controller_props().map_or(storage.device_id(), |_| storage.controller_id());
+    qmp.delete_device(&device, time::Duration::from_secs(UNPLUG_TIMEOUT))
+	.unwrap_or_else(|e| panic!("Cannot detach storage.{}: {}", name, e));
+    for n in storage.node_names() {
+	if let Err(e) = qmp.execute("blockdev-del", json::object!{ "node-name": n.as_str() }) {
+	    println!("{}", e);
+	}
+    }
+    if storage.is_encrypted() {
+	let _ = qmp.execute("object-del", json::object!{ "id": storage.secret_id() });
+    }
+    storage.release_lease();
+    println!("Detached storage.{} from {}", name, conf.name);
+
+    if record.is_some() {
+	let _ = fs::remove_file(hotplug_record(conf, name));
+    }
+    if vm::has_flag(args, "--persist") && record.is_none() {
+	unpersist_section(conf, "storage", name);
+    }
+}
+
+// vm-attach-disk <vm> <file> ..., vm-detach-disk <vm> <section>
+pub fn disk(conf: &vm::VmConf, attach: bool, args: &[String]) {
+    if vm::positional_args(args).len() != 2 {
+	usage();
+    }
+    if attach {
+	attach_disk(conf, args);
+    } else {
+	detach_disk(conf, args);
+    }
+}
+
+// A macvtap or tapbridge section of the toml, by name or as heading.name.
+fn nic_section(conf: &vm::VmConf, name: &str) -> (String, Box<dyn ConfModule>) {
+    let candidates = match name.split_once('.') {
+	Some(_) => vec![String::from(name)],
+	None => vec![format!("macvtap.{}", name), format!("tapbridge.{}", name)],
+    };
+    for c in candidates {
+	let (heading, n) = c.split_once('.').unwrap();
+	if heading != "macvtap" && heading != "tapbridge" {
+	    panic!("{} is not a macvtap or tapbridge section", name);
+	}
+	if let Some(m) = conf.section(heading, n) {
+	    return (c, m);
+	}
+    }
+    panic!("Cannot find a macvtap or tapbridge section {} in {}", name, conf.filename);
+}
+
+// Creating and removing taps needs root, which vm-init has.
+fn run_init(conf: &vm::VmConf, section: &str, deinit: bool) {
+    let mut cmd = Command::new("vm-init");
+    cmd.arg(&conf.name).arg(format!("--section={}", section));
+    if deinit {
+	cmd.arg("--deinit");
+    }
+    let status = cmd.status().expect("Cannot run vm-init");
+    if !status.success() {
+	panic!("vm-init failed for [{}]: {}", section, status);
+    }
+}
+
+fn is_attached(qmp: &mut Qmp, id: &str) -> bool {
+    qmp.execute("qom-list", json::object!{ "path": "/machine/peripheral" })
+	.expect("Cannot list the devices")
+	.members()
+	.any(|p| p["name"] == id)
+}
+
+fn attach_nic(conf: &vm::VmConf, section: &str) {
+    let (section, m) = nic_section(conf, section);
+    let nic = m.as_nic().unwrap();
+    let mut qmp = conf.qmp();
+    // vm-init would replace the link under the running VM.
+    if is_attached(&mut qmp, &nic.device_id()) {
+	panic!("[{}] is already attached to {}", section, conf.name);
+    }
+    run_init(conf, &section, false);
+
+    let fdname = format!("fd-{}", nic.ifname);
+    let r = if nic.macvtap {
+	let tap = fs::OpenOptions::new().read(true).write(true).open(nic.tap_device())
+	    .expect("Cannot open tap device");
+	qmp.send_fd(&fdname, tap.as_raw_fd()).map(|_| json::object!{
+	    "type": "tap",
+	    "id": nic.ifname.as_str(),
+	    "fd": fdname.as_str(),
+	    "vhost": true,
+	})
+    } else {
+	Ok(json::object!{
+	    "type": "tap",
+	    "id": nic.ifname.as_str(),
+	    "ifname": nic.ifname.as_str(),
+	    "script": "no",
+	    "downscript": "no",
+	})
+    };
+    let port = free_hotplug_port(&mut qmp);
+    let mut device = nic.device_props();
+    if let Some(p) = port.as_ref() {
+	device = device.str("bus", p);
+    }
+    let r = r.and_then(|netdev| qmp.execute("netdev_add", netdev))
+	.and_then(|_| qmp.execute("device_add", device.to_json()));
+    if let Err(e) = r {
+	let _ = qmp.execute("netdev_del", json::object!{ "id": nic.ifname.as_str() });
+	if nic.macvtap {
+	    let _ = qmp.execute("closefd", json::object!{ "fdname": fdname.as_str() });
+	}
+	run_init(conf, &section, true);
+	if port.is_none() && e.contains("Bus") {
+	    panic!("Cannot attach [{}]: {}, consider adding hotplug-slots to the base section", section, e);
+	}
+	panic!("Cannot attach [{}]: {}", section, e);
+    }
+    println!("Attached [{}] to {} as {}", section, conf.name, nic.device_id());
+}
+
+fn detach_nic(conf: &vm::VmConf, section: &str) {
+    let (section, m) = nic_section(conf, section);
+    let nic = m.as_nic().unwrap();
+    let mut qmp = conf.qmp();
+    qmp.delete_device(&nic.device_id(), time::Duration::from_secs(UNPLUG_TIMEOUT))
+	.unwrap_or_else(|e| panic!("Cannot detach [{}]: {}", section, e));
+    if let Err(e) = qmp.execute("netdev_del", json::object!{ "id": nic.ifname.as_str() }) {
+	println!("{}", e);
+    }
+    run_init(conf, &section, true);
+    println!("Detached [{}] from {}", section, conf.name);
+}
+
+// vm-attach-nic <vm> <section>, vm-detach-nic <vm> <section>
+pub fn nic(conf: &vm::VmConf, attach: bool, args: &[String]) {
+    let pos = vm::positional_args(args);
+    if pos.len() != 2 {
+	usage();
+    }
+    if attach {
+	attach_nic(conf, &pos[1]);
+    } else {
+	detach_nic(conf, &pos[1]);
+    }
+}
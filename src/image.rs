@@ -0,0 +1,227 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path;
+
+pub const FORMATS: &[&str] = &["raw", "qcow2", "vmdk", "vdi"];
+
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+const QCOW2_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+const VMDK_SPARSE_MAGIC: &[u8] = b"KDMV";
+const VMDK_DESCRIPTOR: &[u8] = b"# Disk DescriptorFile";
+const VDI_SIGNATURE: u32 = 0xbeda_107f;
+const MAX_CHAIN_DEPTH: usize = 64;
+// Raw images are probed, so their headers may come from the guest and every
+// length in them is checked before it is used.
+const QCOW2_BACKING_NAME_MAX: usize = 1023;
+const QCOW2_CLUSTER_BITS: std::ops::RangeInclusive<u32> = 9..=21;
+const VMDK_DESCRIPTOR_MAX: u64 = 64 * 1024;
+
+fn read_at(f: &mut fs::File, offset: u64, len: usize) -> Result<Vec<u8>, String> {
+    let mut buf = vec![0u8; len];
+    f.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+    f.read_exact(&mut buf).map_err(|e| e.to_string())?;
+    Ok(buf)
+}
+
+fn be32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn be64(b: &[u8]) -> u64 {
+    u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+}
+
+fn le32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn le64(b: &[u8]) -> u64 {
+    u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+}
+
+// Guesses the image format from its header, defaulting to raw.
+pub fn detect_format<P: AsRef<path::Path>>(filename: P) -> Result<String, String> {
+    let mut f = fs::File::open(filename.as_ref())
+	.map_err(|e| format!("Cannot open image {}: {}", filename.as_ref().display(), e))?;
+    let mut header = Vec::new();
+    (&mut f).take(512).read_to_end(&mut header).map_err(|e| e.to_string())?;
+
+    let format = if header.starts_with(QCOW2_MAGIC) {
+	"qcow2"
+    } else if header.starts_with(VMDK_SPARSE_MAGIC) || header.starts_with(VMDK_DESCRIPTOR) {
+	"vmdk"
+    } else if header.len() >= 0x44 && le32(&header[0x40..]) == VDI_SIGNATURE {
+	"vdi"
+    } else {
+	"raw"
+    };
+    Ok(String::from(format))
+}
+
+fn resolve_backing(image: &path::Path, backing: &str) -> path::PathBuf {
+    let p = path::Path::new(backing);
+    if p.is_absolute() {
+	return p.to_path_buf();
+    }
+    image.parent().map(|d| d.join(p)).unwrap_or(p.to_path_buf())
+}
+
+// Backing file and its format (if recorded) of a qcow2 image.
+fn qcow2_backing(f: &mut fs::File) -> Result<Option<(String, Option<String>)>, String> {
+    let header = read_at(f, 0, 104)?;
+    let version = be32(&header[4..]);
+    let backing_offset = be64(&header[8..]);
+    let backing_size = be32(&header[16..]) as usize;
+    let cluster_bits = be32(&header[20..]);
+    if backing_offset == 0 {
+	return Ok(None);
+    }
+    if !QCOW2_CLUSTER_BITS.contains(&cluster_bits) {
+	return Err(format!("Invalid cluster size 2^{}", cluster_bits));
+    }
+    // Like qemu, expect the header, its extensions and the backing file name
+    // in the first cluster.
+    let cluster_size = 1u64 << cluster_bits;
+    if backing_size > QCOW2_BACKING_NAME_MAX {
+	return Err(String::from("Backing file name too long"));
+    }
+    if backing_offset.checked_add(backing_size as u64).is_none_or(|end| end > cluster_size) {
+	return Err(String::from("Invalid backing file offset"));
+    }
+    let backing = String::from_utf8(read_at(f, backing_offset, backing_size)?)
+	.map_err(|_| String::from("Invalid backing file name"))?;
+
+    // Header extensions follow the header, up to the backing file name.
+    let mut offset = if version >= 3 { be32(&header[100..]) as u64 } else { 72 };
+    let mut backing_format = None;
+    while offset + 8 <= backing_offset {
+	let ext = read_at(f, offset, 8)?;
+	let (ext_type, ext_len) = (be32(&ext), be32(&ext[4..]) as u64);
+	if ext_type == 0 {
+	    break;
+	}
+	if ext_len > backing_offset - offset - 8 {
+	    return Err(String::from("Invalid header extension"));
+	}
+	if ext_type == QCOW2_EXT_BACKING_FORMAT {
+	    let name = read_at(f, offset + 8, ext_len as usize)?;
+	    backing_format = Some(String::from_utf8_lossy(&name).into_owned());
+	}
+	offset += 8 + ((ext_len + 7) & !7);
+    }
+    Ok(Some((backing, backing_format)))
+}
+
+// Parent of a vmdk differencing disk, from its text or embedded descriptor.
+fn vmdk_backing(f: &mut fs::File) -> Result<Option<(String, Option<String>)>, String> {
+    let header = read_at(f, 0, 64)?;
+    let descriptor = if header.starts_with(VMDK_SPARSE_MAGIC) {
+	let err = || String::from("Invalid vmdk descriptor");
+	let offset = le64(&header[28..]).checked_mul(512).ok_or_else(err)?;
+	let size = le64(&header[36..]).checked_mul(512).ok_or_else(err)?;
+	if offset == 0 {
+	    return Ok(None);
+	}
+	if size > VMDK_DESCRIPTOR_MAX {
+	    return Err(err());
+	}
+	read_at(f, offset, size as usize)?
+    } else {
+	let mut d = Vec::new();
+	f.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+	f.take(VMDK_DESCRIPTOR_MAX).read_to_end(&mut d).map_err(|e| e.to_string())?;
+	d
+    };
+    let descriptor = String::from_utf8_lossy(&descriptor);
+    for line in descriptor.lines() {
+	if let Some(v) = line.trim().strip_prefix("parentFileNameHint=") {
+	    return Ok(Some((String::from(v.trim_matches('"')), Some(String::from("vmdk")))));
+	}
+    }
+    Ok(None)
+}
+
+pub fn backing_file<P: AsRef<path::Path>>(filename: P, format: &str) -> Result<Option<(path::PathBuf, Option<String>)>, String> {
+    let image = filename.as_ref();
+    let mut f = fs::File::open(image).map_err(|e| format!("Cannot open image {}: {}", image.display(), e))?;
+    let backing = match format {
+	"qcow2" => qcow2_backing(&mut f),
+	"vmdk" => vmdk_backing(&mut f),
+	_ => Ok(None),
+    }.map_err(|e| format!("{}: {}", image.display(), e))?;
+    Ok(backing.map(|(b, fmt)| (resolve_backing(image, &b), fmt)))
+}
+
+// Walks the backing chain, making sure every file exists and there is no
+// loop. Returns the chain, top image first.
+pub fn validate_chain<P: AsRef<path::Path>>(filename: P, format: &str) -> Result<Vec<(path::PathBuf, String)>, String> {
+    let mut chain = vec![(filename.as_ref().to_path_buf(), String::from(format))];
+    loop {
+	let (image, fmt) = chain.last().unwrap().clone();
+	let (backing, backing_fmt) = match backing_file(&image, &fmt)? {
+	    Some(b) => b,
+	    None => return Ok(chain),
+	};
+	if !backing.exists() {
+	    return Err(format!("Backing file {} of {} does not exist", backing.display(), image.display()));
+	}
+	let canonical = fs::canonicalize(&backing).map_err(|e| e.to_string())?;
+	if chain.iter().any(|(p, _)| fs::canonicalize(p).map(|c| c == canonical).unwrap_or(false)) {
+	    return Err(format!("Backing chain of {} loops at {}", filename.as_ref().display(), backing.display()));
+	}
+	if chain.len() >= MAX_CHAIN_DEPTH {
+	    return Err(format!("Backing chain of {} is too long", filename.as_ref().display()));
+	}
+	let backing_fmt = match backing_fmt {
+	    Some(f) => f,
+	    None => detect_format(&backing)?,
+	};
+	if !FORMATS.contains(&backing_fmt.as_str()) {
+	    return Err(format!("Backing file {} has unsupported format {}", backing.display(), backing_fmt));
+	}
+	chain.push((backing, backing_fmt));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A version 2 qcow2 header with the given backing file fields.
+    fn qcow2(name: &str, backing_offset: u64, backing_size: u32, cluster_bits: u32) -> path::PathBuf {
+	let mut header = vec![0u8; 104];
+	header[..4].copy_from_slice(QCOW2_MAGIC);
+	header[4..8].copy_from_slice(&2u32.to_be_bytes());
+	header[8..16].copy_from_slice(&backing_offset.to_be_bytes());
+	header[16..20].copy_from_slice(&backing_size.to_be_bytes());
+	header[20..24].copy_from_slice(&cluster_bits.to_be_bytes());
+	header.resize(4096, b'a');
+	let filename = std::env::temp_dir().join(format!("vmman-test-{}-{}.qcow2", std::process::id(), name));
+	fs::write(&filename, &header).unwrap();
+	filename
+    }
+
+    #[test]
+    fn qcow2_backing_name() {
+	let image = qcow2("name", 104, 3, 16);
+	let backing = backing_file(&image, "qcow2");
+	fs::remove_file(&image).unwrap();
+	assert_eq!(backing.unwrap(), Some((image.parent().unwrap().join("aaa"), None)));
+    }
+
+    #[test]
+    fn qcow2_backing_outside_small_cluster() {
+	let image = qcow2("small-cluster", 104, 1000, 9);
+	let backing = backing_file(&image, "qcow2");
+	fs::remove_file(&image).unwrap();
+	assert!(backing.unwrap_err().ends_with("Invalid backing file offset"));
+    }
+
+    #[test]
+    fn qcow2_backing_offset_overflow() {
+	let image = qcow2("overflow", u64::MAX - 2, 16, 16);
+	let backing = backing_file(&image, "qcow2");
+	fs::remove_file(&image).unwrap();
+	assert!(backing.unwrap_err().ends_with("Invalid backing file offset"));
+    }
+}
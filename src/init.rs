@@ -1,10 +1,85 @@
-use std::env;
+use std::{env, fs, path};
+use std::process::Command;
 
 pub mod vm;
 
+// One configuration directory per line, e.g. /home/alice/vm.
+const AUTOSTART_MANIFEST: &str = "/etc/vmman/autostart";
+
+// Runs vm-init in a child process for every autostart VM listed in the
+// manifest, so a failing VM does not stop the others.
+fn init_all() {
+    if unsafe { libc::getuid() } != 0 {
+	panic!("vm-init --all can only be run by root");
+    }
+    let manifest = env::var("VMMAN_MANIFEST").unwrap_or(String::from(AUTOSTART_MANIFEST));
+    let content = fs::read_to_string(&manifest).unwrap_or_else(|e| panic!("Cannot read manifest {}: {}", manifest, e));
+    let exe = env::current_exe().expect("Cannot locate vm-init binary");
+    let mut failed = Vec::new();
+    let mut initialized = 0;
+
+    for confdir in content.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+	if !path::Path::new(confdir).is_dir() {
+	    println!("{}: not a directory, skipping", confdir);
+	    failed.push(String::from(confdir));
+	    continue;
+	}
+	let mgr = vm::VmManager::from_dir(confdir);
+	for (name, conf) in mgr.vmconfs.iter() {
+	    match conf.read_autostart() {
+		Ok(false) => continue,
+		Ok(true) => {}
+		Err(e) => {
+		    println!("{}: {}", conf.filename, e);
+		    failed.push(conf.filename.clone());
+		    continue;
+		}
+	    }
+	    println!("Initializing {}", conf.filename);
+	    let status = Command::new(&exe)
+		.arg(name)
+		.env("VMCONF_DIR", confdir)
+		.status();
+	    match status {
+		Ok(s) if s.success() => {
+		    println!("  {} initialized", conf.filename);
+		    initialized += 1;
+		}
+		Ok(s) => {
+		    println!("  {} failed: {}", conf.filename, s);
+		    failed.push(conf.filename.clone());
+		}
+		Err(e) => {
+		    println!("  {} failed: {}", conf.filename, e);
+		    failed.push(conf.filename.clone());
+		}
+	    }
+	}
+    }
+
+    println!("Initialized {} autostart VMs, {} failures", initialized, failed.len());
+    if !failed.is_empty() {
+	println!("Failed:");
+	for f in failed.iter() {
+	    println!("  {}", f);
+	}
+	std::process::exit(1);
+    }
+}
+
 fn main() {
-    let mut mgr = vm::VmManager::new();
     let args = env::args().collect::<Vec<_>>();
+    if vm::has_flag(&args, "--all") {
+	init_all();
+	return;
+    }
+    let mut mgr = vm::VmManager::new();
     let conf = vm::load_vm(&mut mgr, &args);
-    conf.init();
+    if let Some(section) = vm::flag_value(&args, "--section") {
+	conf.init_section(&section, vm::has_flag(&args, "--deinit"));
+    } else if vm::has_flag(&args, "--deinit") {
+	conf.deinit();
+    } else {
+	conf.init();
+    }
 }
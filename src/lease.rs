@@ -0,0 +1,110 @@
+use std::{fs, io::{ErrorKind, Write}, path, time};
+use toml::value;
+
+// Lease files next to images on shared storage record which host runs the
+// VM, since qemu's own image locks do not work reliably across NFS:
+//
+//   host = "node3"
+//   pid = 4242
+//   time = 1700000000
+//   vm = "my-vm"
+//
+// The pid is only meaningful on the host that wrote the lease, so leases
+// of other hosts are never considered stale automatically.
+
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+	panic!("Cannot get the host name");
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn now() -> u64 {
+    time::SystemTime::now().duration_since(time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn content(vm: &str, pid: u32) -> String {
+    let mut t = value::Table::new();
+    t.insert(String::from("host"), value::Value::String(hostname()));
+    t.insert(String::from("pid"), value::Value::Integer(pid as i64));
+    t.insert(String::from("time"), value::Value::Integer(now() as i64));
+    t.insert(String::from("vm"), value::Value::String(String::from(vm)));
+    toml::to_string(&t).expect("Cannot serialize lease")
+}
+
+fn read(lease: &path::Path) -> Option<value::Table> {
+    fs::read_to_string(lease).ok().and_then(|c| toml::from_str::<value::Table>(&c).ok())
+}
+
+fn holder(t: &value::Table) -> (String, i64) {
+    (t.get("host").and_then(|h| h.as_str()).map(String::from).unwrap_or_default(),
+     t.get("pid").and_then(|p| p.as_integer()).unwrap_or(0))
+}
+
+fn describe(t: &value::Table) -> String {
+    let (host, pid) = holder(t);
+    let age = t.get("time").and_then(|t| t.as_integer()).map(|t| now() as i64 - t).unwrap_or(0);
+    format!("{} (pid {} on {}, {}s ago)",
+	    t.get("vm").and_then(|v| v.as_str()).unwrap_or("?"), pid, host, age)
+}
+
+// Zombies that nobody reaped yet no longer hold the image.
+fn is_alive(pid: i64) -> bool {
+    fs::read_to_string(format!("/proc/{}/stat", pid))
+	.map(|s| s.rsplit(')').next().is_some_and(|r| !r.trim_start().starts_with('Z')))
+	.unwrap_or(false)
+}
+
+fn is_stale(t: &value::Table) -> bool {
+    let (host, pid) = holder(t);
+    host == hostname() && !is_alive(pid)
+}
+
+// Whether the lease is held by the qemu `from` (host, pid) of the VM, i.e.
+// the source of a migration handing the VM over.
+fn is_held_by(t: &value::Table, vm: &str, from: Option<&(String, u32)>) -> bool {
+    from.is_some_and(|(host, pid)| holder(t) == (host.clone(), *pid as i64))
+	&& t.get("vm").and_then(|v| v.as_str()) == Some(vm)
+}
+
+// Creates the lease, breaking it first if its holder is gone or `force` is
+// set, or taking it over from the migration source `from`. O_EXCL makes two
+// hosts racing for the lease see each other.
+pub fn acquire(lease: &path::Path, vm: &str, pid: u32, force: bool, from: Option<&(String, u32)>) {
+    for _ in 0..2 {
+	match fs::OpenOptions::new().write(true).create_new(true).open(lease) {
+	    Ok(mut f) => {
+		f.write_all(content(vm, pid).as_bytes()).unwrap_or_else(|e| panic!("Cannot write lease {}: {}", lease.display(), e));
+		return;
+	    }
+	    Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+		match read(lease) {
+		    Some(t) if force || is_stale(&t) => println!("Breaking lease {} held by {}", lease.display(), describe(&t)),
+		    Some(t) if is_held_by(&t, vm, from) => println!("Taking over lease {} from {}", lease.display(), describe(&t)),
+		    None if force => println!("Breaking unreadable lease {}", lease.display()),
+		    Some(t) => panic!("{} is in use by {}, use --force-unlock if that is stale", lease.display(), describe(&t)),
+		    None => panic!("Cannot read lease {}, use --force-unlock if it is stale", lease.display()),
+		}
+		let _ = fs::remove_file(lease);
+	    }
+	    Err(e) => panic!("Cannot create lease {}: {}", lease.display(), e),
+	}
+    }
+    panic!("Cannot take lease {}", lease.display());
+}
+
+// Points the lease at the qemu process once it runs.
+pub fn update(lease: &path::Path, vm: &str, pid: u32) {
+    fs::write(lease, content(vm, pid)).unwrap_or_else(|e| panic!("Cannot write lease {}: {}", lease.display(), e));
+}
+
+// Removes the lease if this host holds it.
+pub fn release(lease: &path::Path) {
+    if let Some(t) = read(lease) {
+	if holder(&t).0 == hostname() {
+	    let _ = fs::remove_file(lease);
+	}
+    }
+}
@@ -1,18 +1,29 @@
-use std::env;
+use std::{env, path};
 pub mod vm;
 pub mod pull;
+pub mod systemd;
+pub mod console;
+pub mod monitor;
+pub mod migrate;
+pub mod disk;
+pub mod snapshot;
+pub mod clone;
+pub mod backup;
+pub mod throttle;
+pub mod media;
+pub mod hotplug;
 
 fn usage() {
-    println!("Cannot run vmman binary directly. Please run vm-run/vm-init/vm-list/vm-pull.");
+    println!("Cannot run vmman binary directly. Please run vm-run/vm-stop/vm-pause/vm-resume/vm-reset/vm-save/vm-restore/vm-migrate/vm-disk/vm-snapshot/vm-clone/vm-backup/vm-throttle/vm-media/vm-attach-disk/vm-detach-disk/vm-attach-nic/vm-detach-nic/vm-console/vm-monitor/vm-init/vm-list/vm-pull/vm-systemd.");
 }
 
 fn main() {
     let args = env::args().collect::<Vec<_>>();
 
-    match args.get(0) {
+    match args.get(0).and_then(|x| path::Path::new(x).file_name()).and_then(|x| x.to_str()) {
 	Some(x) => {
 	    let mut mgr = vm::VmManager::new();
-	    match x.as_str() {
+	    match x {
 		"vm-list" => {
 		    for (_, x) in mgr.vmconfs.iter() {
 			println!("  {} in {}", x.name, x.filename)
@@ -20,11 +31,88 @@ fn main() {
 		}
 		"vm-run" => {
 		    let conf = vm::load_vm(&mut mgr, &args);
-		    conf.run()
+		    if vm::has_flag(&args, "--supervise") {
+			conf.supervise()
+		    } else if let Some(uri) = vm::flag_value(&args, "--incoming") {
+			conf.run_incoming(&uri)
+		    } else {
+			conf.run()
+		    }
+		}
+		"vm-stop" => {
+		    let conf = vm::load_vm(&mut mgr, &args);
+		    conf.stop(vm::has_flag(&args, "--force"))
+		}
+		"vm-pause" => {
+		    vm::load_vm(&mut mgr, &args).pause();
+		}
+		"vm-resume" => {
+		    vm::load_vm(&mut mgr, &args).resume();
+		}
+		"vm-reset" => {
+		    vm::load_vm(&mut mgr, &args).reset();
+		}
+		"vm-save" => {
+		    let file = vm::positional_args(&args).get(1).cloned().expect("Expecting a file to save to");
+		    vm::load_vm(&mut mgr, &args).save(&file);
+		}
+		"vm-restore" => {
+		    let file = vm::positional_args(&args).get(1).cloned().expect("Expecting a saved state file");
+		    vm::load_vm(&mut mgr, &args).restore(&file);
+		}
+		"vm-migrate" => {
+		    let conf = vm::load_vm(&mut mgr, &args);
+		    migrate::migrate(conf, &args);
+		}
+		"vm-disk" => {
+		    disk::disk(&mut mgr, &args);
+		}
+		"vm-snapshot" => {
+		    snapshot::snapshot(&mut mgr, &args);
+		}
+		"vm-clone" => {
+		    clone::clone(&mut mgr, &args);
+		}
+		"vm-backup" => {
+		    backup::backup(&mut mgr, &args);
+		}
+		"vm-throttle" => {
+		    let conf = vm::load_vm(&mut mgr, &args);
+		    throttle::throttle(conf, &args);
+		}
+		"vm-media" => {
+		    media::media(&mut mgr, &args);
+		}
+		"vm-attach-disk" => {
+		    let conf = vm::load_vm(&mut mgr, &args);
+		    hotplug::disk(conf, true, &args);
+		}
+		"vm-detach-disk" => {
+		    let conf = vm::load_vm(&mut mgr, &args);
+		    hotplug::disk(conf, false, &args);
+		}
+		"vm-attach-nic" => {
+		    let conf = vm::load_vm(&mut mgr, &args);
+		    hotplug::nic(conf, true, &args);
+		}
+		"vm-detach-nic" => {
+		    let conf = vm::load_vm(&mut mgr, &args);
+		    hotplug::nic(conf, false, &args);
+		}
+		"vm-console" => {
+		    let conf = vm::load_vm(&mut mgr, &args);
+		    console::attach(conf);
+		}
+		"vm-monitor" => {
+		    let conf = vm::load_vm(&mut mgr, &args);
+		    monitor::monitor(conf, &args);
 		}
 		"vm-pull" => {
 		    pull::pull();
 		}
+		"vm-systemd" => {
+		    systemd::generate(&mut mgr, &args);
+		}
 		_ => {
 		    usage();
 		    return;
@@ -0,0 +1,81 @@
+use std::{env, fs, path};
+use crate::vm::{self, modules::storage::StorageModule};
+
+fn usage() -> ! {
+    println!("Usage: vm-media <vm> eject [--section=NAME]");
+    println!("       vm-media <vm> insert <iso> [--section=NAME]");
+    std::process::exit(1);
+}
+
+// ISOs of the user, which `insert` finds by their short name.
+fn iso_dir(mgr: &vm::VmManager) -> path::PathBuf {
+    env::var("VMMAN_ISO_DIR").map(path::PathBuf::from)
+	.unwrap_or(path::Path::new(&mgr.confdir).join("iso"))
+}
+
+fn resolve_iso(dir: &path::Path, iso: &str) -> path::PathBuf {
+    let p = path::Path::new(iso);
+    if p.exists() || iso.contains('/') {
+	return fs::canonicalize(p).unwrap_or_else(|e| panic!("Cannot find {}: {}", iso, e));
+    }
+    for name in [String::from(iso), format!("{}.iso", iso)].iter() {
+	let p = dir.join(name);
+	if p.exists() {
+	    return p;
+	}
+    }
+    let mut available = fs::read_dir(dir).map(|entries| {
+	entries.filter_map(|e| e.ok())
+	    .filter_map(|e| e.file_name().into_string().ok())
+	    .map(|n| String::from(n.strip_suffix(".iso").unwrap_or(&n)))
+	    .collect::<Vec<_>>()
+    }).unwrap_or_default();
+    available.sort();
+    panic!("Cannot find {} in {}, available: {}", iso, dir.display(), available.join(", "));
+}
+
+// The cdrom drive to work on, which must be named when there are several.
+fn cdrom(conf: &vm::VmConf, section: Option<String>) -> &StorageModule {
+    let drives = conf.modules.iter()
+	.filter_map(|m| m.as_storage())
+	.filter(|s| s.is_cdrom())
+	.collect::<Vec<_>>();
+    match section {
+	Some(s) => drives.into_iter().find(|d| d.name == s).unwrap_or_else(|| panic!("Cannot find cdrom storage.{}", s)),
+	None if drives.len() == 1 => drives[0],
+	None if drives.is_empty() => panic!("{} has no storage with media = \"cdrom\"", conf.name),
+	None => panic!("{} has several cdrom drives, use --section=NAME", conf.name),
+    }
+}
+
+// vm-media <vm> eject|insert [iso]
+pub fn media(mgr: &mut vm::VmManager, args: &[String]) {
+    let pos = vm::positional_args(args);
+    if pos.len() < 2 {
+	usage();
+    }
+    let dir = iso_dir(mgr);
+    let conf = mgr.vmconfs.get_mut(&pos[0]).unwrap_or_else(|| panic!("Cannot find machine {}", pos[0]));
+    conf.load();
+    let drive = cdrom(conf, vm::flag_value(args, "--section"));
+    let mut qmp = conf.qmp();
+    let id = drive.device_id();
+    match (pos[1].as_str(), pos.get(2)) {
+	("eject", None) => {
+	    qmp.execute("eject", json::object!{ "id": id.as_str(), "force": true })
+		.unwrap_or_else(|e| panic!("Cannot eject storage.{}: {}", drive.name, e));
+	    println!("Ejected storage.{}", drive.name);
+	}
+	("insert", Some(iso)) => {
+	    let iso = resolve_iso(&dir, iso);
+	    qmp.execute("blockdev-change-medium", json::object!{
+		"id": id.as_str(),
+		"filename": iso.to_str().unwrap(),
+		"format": "raw",
+		"read-only-mode": "read-only",
+	    }).unwrap_or_else(|e| panic!("Cannot insert {}: {}", iso.display(), e));
+	    println!("Inserted {} into storage.{}", iso.display(), drive.name);
+	}
+	_ => usage(),
+    }
+}
@@ -0,0 +1,93 @@
+use std::process::Command;
+use std::{env, thread::sleep, time};
+use crate::vm::{self, modules::lease};
+
+const DEFAULT_PORT: u16 = 4444;
+const CONNECT_RETRIES: u32 = 30;
+
+fn is_local(host: &str) -> bool {
+    host == "localhost" || host == "127.0.0.1" || host == "::1"
+}
+
+// Runs a vm-* command on the destination, through ssh unless it is this
+// host. The destination shares the configuration directory over NFS.
+fn run_on(host: &str, cmd: &[&str], env: &[(&str, String)]) -> Result<(), String> {
+    let status = if is_local(host) {
+	Command::new(cmd[0]).args(&cmd[1..])
+	    .envs(env.iter().map(|(k, v)| (*k, v.as_str())))
+	    .status()
+    } else {
+	let remote = env.iter().map(|(k, v)| format!("{}={}", k, vm::shell_quote(v)))
+	    .chain(cmd.iter().map(|c| vm::shell_quote(c)))
+	    .collect::<Vec<_>>()
+	    .join(" ");
+	Command::new("ssh").args(["-n", host, &format!("{} >/dev/null 2>&1", remote)]).status()
+    };
+    match status {
+	Ok(s) if s.success() => Ok(()),
+	Ok(s) => Err(format!("{} on {} failed: {}", cmd.join(" "), host, s)),
+	Err(e) => Err(format!("Cannot run {} on {}: {}", cmd.join(" "), host, e)),
+    }
+}
+
+// vm-migrate <name> <dest-host> [--port=N]
+//
+// Only memory is moved: the storage files are expected on shared NFS.
+pub fn migrate(conf: &vm::VmConf, args: &[String]) {
+    let dest = vm::positional_args(args).get(1).cloned().expect("Expecting a destination host");
+    let port = vm::flag_value(args, "--port")
+	.map(|p| p.parse::<u16>().expect("Invalid port"))
+	.unwrap_or(DEFAULT_PORT);
+
+    let mut qmp = conf.qmp();
+    let pid = conf.qemu_pid().expect("Cannot find the qemu pid of the source");
+    let mut env = Vec::new();
+    if let Ok(d) = env::var("VMCONF_DIR") {
+	env.push(("VMCONF_DIR", d));
+    }
+
+    if is_local(&dest) {
+	// The source already holds the network resources, and the destination
+	// needs its own runtime dir next to the source's.
+	let rundir = conf.runtime_dir();
+	let base = rundir.parent().expect("Cannot find runtime dir");
+	env.push(("VMMAN_RUNTIME_DIR", format!("{}/migrate-{}", base.display(), port)));
+    } else {
+	println!("Initializing {} on {}", conf.name, dest);
+	run_on(&dest, &["vm-init", &conf.name], &env).expect("Cannot init the VM on the destination");
+    }
+
+    println!("Starting incoming {} on {}:{}", conf.name, dest, port);
+    let from = format!("--migrate-from={}:{}", lease::hostname(), pid);
+    run_on(&dest, &["vm-run", &format!("--incoming=tcp:0:{}", port), &from, &conf.name], &env)
+	.expect("Cannot start the VM on the destination");
+
+    let uri = format!("tcp:{}:{}", dest, port);
+    let mut retries = 0;
+    loop {
+	qmp.execute("migrate", json::object!{ "uri": uri.as_str() }).expect("Cannot start migration");
+	match vm::wait_migration(&mut qmp) {
+	    Ok(()) => break,
+	    // The destination may not be listening yet.
+	    Err(e) if retries < CONNECT_RETRIES && e.contains("refused") => {
+		retries += 1;
+		sleep(time::Duration::from_secs(1));
+	    }
+	    Err(e) => {
+		println!("Migration failed: {}", e);
+		println!("Stopping the destination, {} keeps running here", conf.name);
+		if let Err(e) = run_on(&dest, &["vm-stop", "--force", &conf.name], &env) {
+		    println!("{}", e);
+		}
+		// Stopping the destination gave back the leases it took over.
+		for s in conf.modules.iter().filter_map(|m| m.as_storage()) {
+		    s.restore_lease(pid);
+		}
+		std::process::exit(1);
+	    }
+	}
+    }
+
+    println!("{} is running on {}, stopping the source", conf.name, dest);
+    qmp.execute("quit", json::JsonValue::new_object()).expect("Cannot quit the source qemu");
+}
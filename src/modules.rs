@@ -3,11 +3,38 @@ use toml::value;
 use std::{io::Write, os::unix::prelude::{AsRawFd, MetadataExt}, path, thread::sleep, time};
 use std::fs;
 use std::process::Command;
+use super::image;
+
+#[path = "storage.rs"] pub mod storage;
+#[path = "policy.rs"] mod policy;
+#[path = "lease.rs"] pub mod lease;
+#[path = "secret.rs"] pub mod secret;
+
+// Original owners of the devices handed over by vm-init, so that deinit can
+// give them back.
+const PERM_STATE_DIR: &str = "/run/vmman/perm";
+
+// Where a VM lives, for modules that keep state next to it.
+pub struct VmEnv {
+    pub name: String,
+    pub confdir: path::PathBuf,
+    pub rundir: path::PathBuf,
+    pub ephemeral: bool,
+    pub force_unlock: bool,
+    pub migrate_from: Option<(String, u32)>,
+}
+
+pub const CONSOLE_SOCKET: &str = "console.sock";
+const CONSOLE_LOGS_KEPT: u32 = 5;
 
 pub trait ConfModule {
     fn init(&self, _: u32, _: u32) {}
+    fn deinit(&self, _: u32, _: u32) {}
     fn startup_args(&mut self) -> Vec<String>;
-    fn post_startup(&mut self) {}
+    fn post_startup(&mut self, _: u32) {}
+    fn cleanup(&mut self) {}
+    fn as_storage(&self) -> Option<&storage::StorageModule> { None }
+    fn as_nic(&self) -> Option<&BaseTapModule> { None }
 }
 
 fn get_string(conf: &value::Table, key: &str) -> String {
@@ -19,23 +46,21 @@ fn get_option_string(conf: &value::Table, key: &str) -> Option<String> {
     return conf.get(key).map(|x| x.as_str()).flatten().map(|x| String::from(x));
 }
 
-pub fn create_module(heading: &str, section: &value::Table) -> Box<dyn ConfModule> {
+pub fn create_module(heading: &str, name: &str, section: &value::Table, vmenv: &VmEnv) -> Box<dyn ConfModule> {
     match heading {
 	"macvtap" => Box::new(MacVTapModule::new(section)),
 	"tapbridge" => Box::new(BridgeTapModule::new(section)),
+	"usb-passthrough" => Box::new(UsbModule::new(name, section)),
 	"pcie-passthrough" => Box::new(VfioModule {
 	    name: get_string(section, "dev"),
 	    romfile: get_option_string(section, "romfile"),
 	}),
-	"base" => Box::new(BaseModule::new(section)),
+	"base" => Box::new(BaseModule::new(section, vmenv)),
 	"apple-smc" => Box::new(AppleSMCModule {
 	    osk: get_string(section, "osk"),
 	}),
-	"storage" => Box::new(StorageModule {
-	    driver: get_string(section, "driver"),
-	    filename: get_string(section, "file"),
-	    media: get_option_string(section, "media"),
-	}),
+	"storage" => Box::new(storage::StorageModule::new(name, section, vmenv)),
+	"throttle" => Box::new(storage::ThrottleModule::new(name, section)),
 	"bridge" => {
 	    panic!("'bridge' deprecated, consider using 'macvtap'/'tapbridge' module");
 	}
@@ -75,19 +100,117 @@ fn init_perm<P: AsRef<path::Path>>(path: &P, uid: u32, gid: u32) {
     }
 }
 
+fn perm_state_file(path: &path::Path) -> path::PathBuf {
+    path::Path::new(PERM_STATE_DIR).join(path.to_str().unwrap().replace('/', "%"))
+}
+
+// init_perm() for devices that users pick themselves: the device has to be
+// allowed by the policy. Its original owner and the uid it was handed to are
+// recorded for restore_perm() as <uid>:<gid>:<granted uid>.
+fn init_perm_checked(kind: &str, pathname: &str, uid: u32, gid: u32) {
+    init_perm_allowed(kind, pathname, pathname, uid, gid);
+}
+
+// Same, for devices that the policy names by something more stable than
+// their device node.
+fn init_perm_allowed(kind: &str, item: &str, pathname: &str, uid: u32, gid: u32) {
+    if pathname.split('/').any(|c| c == "..") || !policy::allowed(kind, uid, item) {
+	panic!("{} is not allowed for uid {} by the {} policy", item, uid, kind);
+    }
+    let path = path::Path::new(pathname);
+    let md = fs::metadata(path).unwrap_or_else(|e| panic!("Cannot access metadata for file {}: {}", pathname, e));
+    let state = perm_state_file(path);
+    // A device handed over before keeps its original owner.
+    let (orig_uid, orig_gid) = match read_perm_state(&state) {
+	Some((o_uid, o_gid, _)) => (o_uid, o_gid),
+	None => (md.uid(), md.gid()),
+    };
+    fs::create_dir_all(PERM_STATE_DIR).unwrap_or_else(|e| panic!("Cannot create {}: {}", PERM_STATE_DIR, e));
+    fs::write(&state, format!("{}:{}:{}", orig_uid, orig_gid, uid))
+	.unwrap_or_else(|e| panic!("Cannot record the owner of {}: {}", pathname, e));
+    init_perm(&path, uid, gid);
+}
+
+// Records of older versions lack the granted uid.
+fn read_perm_state(state: &path::Path) -> Option<(u32, u32, Option<u32>)> {
+    let owner = fs::read_to_string(state).ok()?;
+    let err = format!("Invalid owner record {}", state.to_str().unwrap());
+    let ids = owner.trim().split(':').map(|x| x.parse::<u32>().expect(&err)).collect::<Vec<_>>();
+    match ids.as_slice() {
+	[uid, gid] => Some((*uid, *gid, None)),
+	[uid, gid, granted] => Some((*uid, *gid, Some(*granted))),
+	_ => panic!("{}", err),
+    }
+}
+
+// Only the user the device was handed to, and who is still allowed to have
+// it, can give it back, so that a toml naming the device of another user
+// cannot take it away from the VM of that user.
+fn restore_perm(kind: &str, item: &str, pathname: &str, uid: u32) {
+    let path = path::Path::new(pathname);
+    let state = perm_state_file(path);
+    let (orig_uid, orig_gid, granted) = match read_perm_state(&state) {
+	Some(s) => s,
+	None => {
+	    println!("{} was not initialized by vm-init, skipping", pathname);
+	    return;
+	}
+    };
+    if granted.is_some_and(|g| g != uid) || !policy::allowed(kind, uid, item) {
+	panic!("{} was not handed to uid {}, refusing to restore its owner", pathname, uid);
+    }
+    let (uid, gid) = (orig_uid, orig_gid);
+    println!("Restoring Permissions on {}", pathname);
+    init_perm(&path, uid, gid);
+    fs::remove_file(&state).unwrap_or_else(|e| panic!("Cannot remove {}: {}", state.to_str().unwrap(), e));
+}
+
 // Base Tap/Network struct
-struct BaseTapModule {
-    ifname: String,
+pub struct BaseTapModule {
+    pub ifname: String,
     macaddress: String,
     driver: String,
+    pub macvtap: bool,
 }
 
 impl BaseTapModule {
-    pub fn new(conf: &value::Table) -> BaseTapModule {
+    pub fn new(conf: &value::Table, macvtap: bool) -> BaseTapModule {
 	return BaseTapModule {
 	    ifname: get_string(conf, "interface"),
 	    macaddress: get_string(conf, "mac"),
-	    driver: get_string(conf, "driver")
+	    driver: get_string(conf, "driver"),
+	    macvtap,
+	}
+    }
+
+    // The character device of a macvtap interface.
+    pub fn tap_device(&self) -> String {
+	format!("/dev/tap{}", self.ifidx())
+    }
+
+    pub fn device_id(&self) -> String {
+	format!("nic-{}", self.ifname)
+    }
+
+    pub fn device_props(&self) -> storage::Props {
+	storage::Props::new()
+	    .str("driver", &self.driver)
+	    .str("id", &self.device_id())
+	    .str("netdev", &self.ifname)
+	    .str("mac", &self.macaddress)
+    }
+
+    fn remove_link(&self) {
+	if !build_path("/sys/class/net", &self.ifname, "").exists() {
+	    return;
+	}
+	println!("Removing link {}", &self.ifname);
+	let p = Command::new("ip")
+	    .args(["link", "del", &self.ifname])
+	    .output()
+	    .unwrap_or_else(|e| panic!("Cannot run ip link to delete the tap {}: {}", &self.ifname, e));
+	if !p.status.success() {
+	    panic!("{}", String::from_utf8_lossy(&p.stderr));
 	}
     }
 
@@ -139,8 +262,7 @@ impl BaseTapModule {
     }
 
     fn startup_args_base(&mut self) -> Vec<String> {
-	return vec![String::from("-device"),
-		    format!("{},netdev={},mac={}", &self.driver, &self.ifname, &self.macaddress)];
+	return vec![String::from("-device"), self.device_props().to_arg_implied("driver")];
     }
 }
 
@@ -153,7 +275,7 @@ struct MacVTapModule {
 impl MacVTapModule {
     pub fn new(conf: &value::Table) -> MacVTapModule {
 	return MacVTapModule {
-	    base: BaseTapModule::new(conf),
+	    base: BaseTapModule::new(conf, true),
 	    ifhost: get_string(conf, "host-interface")
 	}
     }
@@ -175,8 +297,14 @@ impl ConfModule for MacVTapModule {
 	sleep(time::Duration::from_secs(2));
 	init_perm(&format!("/dev/tap{}", ifidx), uid, gid);
     }
+    fn deinit(&self, _uid: u32, _gid: u32) {
+	self.base.remove_link();
+    }
+    fn as_nic(&self) -> Option<&BaseTapModule> {
+	Some(&self.base)
+    }
     fn startup_args(&mut self) -> Vec<String> {
-	let tapfile = fs::OpenOptions::new().read(true).write(true).open(format!("/dev/tap{}", self.base.ifidx())).expect("Cannot open tap device");
+	let tapfile = fs::OpenOptions::new().read(true).write(true).open(self.base.tap_device()).expect("Cannot open tap device");
 	let fd = tapfile.as_raw_fd();
 
 	unsafe {
@@ -197,7 +325,7 @@ struct BridgeTapModule {
 impl BridgeTapModule {
     pub fn new(conf: &value::Table) -> BridgeTapModule {
 	return BridgeTapModule {
-	    base: BaseTapModule::new(conf),
+	    base: BaseTapModule::new(conf, false),
 	    ifbr: get_string(conf, "bridge")
 	}
     }
@@ -220,7 +348,12 @@ impl ConfModule for BridgeTapModule {
 	    }
 	}
     }
-    
+    fn deinit(&self, _uid: u32, _gid: u32) {
+	self.base.remove_link();
+    }
+    fn as_nic(&self) -> Option<&BaseTapModule> {
+	Some(&self.base)
+    }
     
     fn startup_args(&mut self) -> Vec<String> {
 	let mut args = vec![String::from("-netdev"), format!("tap,id={},ifname={},script=no,downscript=no", &self.base.ifname, &self.base.ifname)];
@@ -291,6 +424,117 @@ impl ConfModule for VfioModule {
     }
 }
 
+const USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+// A host USB device found in sysfs.
+struct UsbDevice {
+    id: String,
+    busnum: u32,
+    devnum: u32,
+}
+
+impl UsbDevice {
+    fn node(&self) -> String {
+	format!("/dev/bus/usb/{:03}/{:03}", self.busnum, self.devnum)
+    }
+}
+
+// USB passthrough, with the device picked by any of
+//   device = "vendor:product", serial = "...", port = "bus-port", e.g. "1-2.3"
+// where all given keys have to match a single device.
+struct UsbModule {
+    name: String,
+    device: Option<String>,
+    serial: Option<String>,
+    port: Option<String>,
+}
+
+impl UsbModule {
+    fn new(name: &str, conf: &value::Table) -> UsbModule {
+	let m = UsbModule {
+	    name: String::from(name),
+	    device: get_option_string(conf, "device").map(|d| d.to_lowercase()),
+	    serial: get_option_string(conf, "serial"),
+	    port: get_option_string(conf, "port"),
+	};
+	if m.device.is_none() && m.serial.is_none() && m.port.is_none() {
+	    panic!("usb-passthrough.{} needs a device, serial or port", name);
+	}
+	m
+    }
+
+    fn find(&self) -> Result<UsbDevice, String> {
+	let read = |dir: &path::Path, attr: &str| {
+	    fs::read_to_string(dir.join(attr)).map(|s| String::from(s.trim())).unwrap_or_default()
+	};
+	let entries = fs::read_dir(USB_DEVICES).map_err(|e| format!("Cannot list {}: {}", USB_DEVICES, e))?;
+	let mut found = Vec::new();
+	for entry in entries.filter_map(|e| e.ok()) {
+	    let port = entry.file_name().into_string().unwrap_or_default();
+	    // Interfaces have a ':' in their names, root hubs are usbN.
+	    if port.contains(':') || port.starts_with("usb") {
+		continue;
+	    }
+	    let dir = entry.path();
+	    let id = format!("{}:{}", read(&dir, "idVendor"), read(&dir, "idProduct"));
+	    if self.device.as_ref().is_some_and(|d| d != &id)
+		|| self.serial.as_ref().is_some_and(|s| s != &read(&dir, "serial"))
+		|| self.port.as_ref().is_some_and(|p| p != &port) {
+		continue;
+	    }
+	    let err = format!("Cannot read the address of USB device {}", port);
+	    found.push(UsbDevice {
+		id,
+		busnum: read(&dir, "busnum").parse().map_err(|_| err.clone())?,
+		devnum: read(&dir, "devnum").parse().map_err(|_| err.clone())?,
+	    });
+	}
+	match found.len() {
+	    1 => Ok(found.pop().unwrap()),
+	    0 => Err(format!("No USB device matches usb-passthrough.{}", self.name)),
+	    n => Err(format!("{} USB devices match usb-passthrough.{}, add a serial or port", n, self.name)),
+	}
+    }
+
+    fn controller_id(&self) -> String {
+	format!("xhci-usb-{}", self.name)
+    }
+}
+
+impl ConfModule for UsbModule {
+    // The policy lists vendor:product ids, the device node changes each
+    // time the device is plugged in.
+    fn init(&self, uid: u32, gid: u32) {
+	let dev = self.find().unwrap_or_else(|e| panic!("{}", e));
+	println!("usb-passthrough.{} is {} at {}", self.name, dev.id, dev.node());
+	init_perm_allowed("usb", &dev.id, &dev.node(), uid, gid);
+    }
+
+    fn deinit(&self, uid: u32, _gid: u32) {
+	match self.find() {
+	    Ok(dev) => restore_perm("usb", &dev.id, &dev.node(), uid),
+	    Err(e) => println!("{}, skipping", e),
+	}
+    }
+
+    fn startup_args(&mut self) -> Vec<String> {
+	let dev = self.find().unwrap_or_else(|e| panic!("{}", e));
+	vec![String::from("-device"),
+	     storage::Props::new()
+		 .str("driver", "qemu-xhci")
+		 .str("id", &self.controller_id())
+		 .to_arg_implied("driver"),
+	     String::from("-device"),
+	     storage::Props::new()
+		 .str("driver", "usb-host")
+		 .str("id", &format!("usb-{}", self.name))
+		 .str("bus", &format!("{}.0", self.controller_id()))
+		 .int("hostbus", dev.busnum as u64)
+		 .int("hostaddr", dev.devnum as u64)
+		 .to_arg_implied("driver")]
+    }
+}
+
 struct BaseModule {
     machine: String,
     cpu: Option<String>,
@@ -301,10 +545,13 @@ struct BaseModule {
     vga: Option<String>,
     display: Option<String>,
     serial: Option<String>,
+    hotplug_slots: i64,
+    console_socket: path::PathBuf,
+    console_log: path::PathBuf,
 }
 
 impl BaseModule {
-    fn new(conf: &value::Table) -> BaseModule {
+    fn new(conf: &value::Table, vmenv: &VmEnv) -> BaseModule {
 	return BaseModule {
 	    machine: get_string(conf, "machine"),
 	    cpu: get_option_string(conf, "cpu"),
@@ -315,7 +562,39 @@ impl BaseModule {
 	    vga: get_option_string(conf, "vga"),
 	    display: get_option_string(conf, "display"),
 	    serial: get_option_string(conf, "serial"),
+	    hotplug_slots: conf.get("hotplug-slots")
+		.map(|v| v.as_integer().expect("Expecting hotplug-slots as an integer")).unwrap_or(0),
+	    console_socket: vmenv.rundir.join(CONSOLE_SOCKET),
+	    console_log: get_option_string(conf, "console-log").map(path::PathBuf::from)
+		.unwrap_or(build_path(vmenv.confdir.to_str().unwrap(), "log", &format!("{}-console.log", vmenv.name))),
+	}
+    }
+
+    // Keeps the last few console logs as console.log.1, console.log.2...
+    fn rotate_console_log(&self) {
+	if let Some(dir) = self.console_log.parent() {
+	    fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Cannot create log dir {}: {}", dir.to_str().unwrap(), e));
+	}
+	let log = self.console_log.to_str().unwrap();
+	for i in (1..CONSOLE_LOGS_KEPT).rev() {
+	    let _ = fs::rename(format!("{}.{}", log, i), format!("{}.{}", log, i + 1));
 	}
+	let _ = fs::rename(log, format!("{}.1", log));
+    }
+
+    // Managed serial console: a unix socket for vm-console, logged to a file.
+    fn console_args(&self) -> Vec<String> {
+	self.rotate_console_log();
+	vec![String::from("-chardev"),
+	     storage::Props::new()
+		 .str("backend", "socket")
+		 .str("id", "console")
+		 .str("path", self.console_socket.to_str().unwrap())
+		 .bool("server", true)
+		 .bool("wait", false)
+		 .str("logfile", self.console_log.to_str().unwrap())
+		 .to_arg_implied("backend"),
+	     String::from("-serial"), String::from("chardev:console")]
     }
 }
 
@@ -340,6 +619,16 @@ impl ConfModule for BaseModule {
 	if self.serial.is_some() {
 	    r.push(String::from("-serial"));
 	    r.push(String::from(self.serial.as_ref().unwrap()));
+	} else {
+	    r.extend(self.console_args());
+	}
+	// PCIe devices can only be hot-plugged into root ports on q35, while
+	// the root bus of older machines takes them directly.
+	if self.machine.contains("q35") {
+	    for i in 0..self.hotplug_slots {
+		r.push(String::from("-device"));
+		r.push(format!("pcie-root-port,id=hotplug{},chassis={}", i, i + 1));
+	    }
 	}
 	r.push(String::from("-vga"));
 	r.push(String::from(self.vga.as_ref().map(|s| s.as_str()).unwrap_or("none")));
@@ -359,18 +648,3 @@ impl ConfModule for AppleSMCModule {
 	return vec![String::from("-device"), format!("isa-applesmc,osk={}", &self.osk)];
     }
 }
-
-struct StorageModule {
-    driver: String,
-    filename: String,
-    media: Option<String>,
-}
-
-impl ConfModule for StorageModule {
-    fn startup_args(&mut self) -> Vec<String> {
-	return vec![String::from("-drive"),
-		    format!("if={},format=raw,aio=native,cache.direct=on,file={}{}",
-			    &self.driver, &self.filename,
-			    self.media.as_ref().map(|s| String::from(",media=") + s).unwrap_or(String::from("")))]
-    }
-}
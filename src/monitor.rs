@@ -0,0 +1,115 @@
+use std::io::{self, BufRead, Write};
+use std::{env, fs, path};
+use crate::vm::{self, qmp::Qmp};
+
+const HISTORY_MAX: usize = 1000;
+
+fn history_file() -> path::PathBuf {
+    path::PathBuf::from(env::var("HOME").expect("Cannot get home dir from the system!"))
+	.join(".vmman_monitor_history")
+}
+
+// Lines starting with '{' are raw QMP messages, anything else goes to the
+// human monitor.
+fn run_command(qmp: &mut Qmp, line: &str) -> Result<String, String> {
+    if line.starts_with('{') {
+	let r = qmp.send_raw(line)?;
+	let out = json::stringify_pretty(r.clone(), 2);
+	if r.has_key("error") {
+	    return Err(out);
+	}
+	Ok(out)
+    } else {
+	qmp.hmp(line)
+    }
+}
+
+fn print_events(qmp: &mut Qmp) {
+    for e in qmp.events.drain(..) {
+	println!("event: {}", e.dump());
+    }
+}
+
+// Expands !! and !N against the history.
+fn expand_history(line: &str, history: &[String]) -> Result<String, String> {
+    if line == "!!" {
+	return history.last().cloned().ok_or(String::from("No history"));
+    }
+    if let Some(n) = line.strip_prefix('!') {
+	let idx = n.parse::<usize>().map_err(|_| format!("Invalid history reference {}", line))?;
+	return history.get(idx.wrapping_sub(1)).cloned().ok_or(format!("No history entry {}", idx));
+    }
+    Ok(String::from(line))
+}
+
+fn interactive(qmp: &mut Qmp, name: &str) {
+    let hist_path = history_file();
+    let mut history = fs::read_to_string(&hist_path).unwrap_or_default()
+	.lines().map(String::from).collect::<Vec<_>>();
+    println!("Connected to the monitor of {}. Type '{{...}}' for raw QMP, 'history' to list history, 'quit' to exit.", name);
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+	print_events(qmp);
+	print!("({}) ", name);
+	io::stdout().flush().unwrap();
+	let line = match lines.next() {
+	    Some(Ok(l)) => l,
+	    _ => break,
+	};
+	let line = line.trim();
+	if line.is_empty() {
+	    continue;
+	}
+	if line == "quit" || line == "exit" {
+	    break;
+	}
+	if line == "history" {
+	    for (i, h) in history.iter().enumerate() {
+		println!("{:5}  {}", i + 1, h);
+	    }
+	    continue;
+	}
+	let cmd = match expand_history(line, &history) {
+	    Ok(c) => c,
+	    Err(e) => {
+		println!("{}", e);
+		continue;
+	    }
+	};
+	if cmd != line {
+	    println!("{}", cmd);
+	}
+	match run_command(qmp, &cmd) {
+	    Ok(out) => print!("{}", if out.ends_with('\n') || out.is_empty() { out } else { out + "\n" }),
+	    Err(e) => println!("Error: {}", e),
+	}
+	history.push(cmd);
+    }
+
+    let skip = history.len().saturating_sub(HISTORY_MAX);
+    let content = history[skip..].iter().map(|h| h.clone() + "\n").collect::<String>();
+    if let Err(e) = fs::write(&hist_path, content) {
+	println!("Cannot save history to {}: {}", hist_path.display(), e);
+    }
+}
+
+// vm-monitor <name> [command...]
+pub fn monitor(conf: &vm::VmConf, args: &[String]) {
+    // VMs started by older versions only have the main socket.
+    let sock = Some(conf.monitor_socket()).filter(|s| s.exists()).unwrap_or(conf.qmp_socket());
+    let mut qmp = Qmp::connect(sock).unwrap_or_else(|e| panic!("Cannot connect to the monitor of {}: {}", conf.name, e));
+    let cmd = args.iter().skip(1).skip_while(|a| a.starts_with("--")).skip(1).cloned().collect::<Vec<_>>();
+    if cmd.is_empty() {
+	interactive(&mut qmp, &conf.name);
+	return;
+    }
+    match run_command(&mut qmp, &cmd.join(" ")) {
+	Ok(out) => print!("{}", if out.ends_with('\n') || out.is_empty() { out } else { out + "\n" }),
+	Err(e) => {
+	    println!("Error: {}", e);
+	    std::process::exit(1);
+	}
+    }
+}
@@ -0,0 +1,60 @@
+use std::{env, ffi, fs};
+use toml::value;
+
+// Host devices that vm-init may hand over to users, e.g.
+//
+//   [block]
+//   alice = ["/dev/vg0/alice-*", "/dev/nvme1n1"]
+//
+// Keys are user names, or "*" for everybody. An entry ending with '*'
+// matches any item starting with the rest of it.
+const POLICY_FILE: &str = "/etc/vmman/policy.toml";
+
+fn user_name(uid: u32) -> Option<String> {
+    let pw = unsafe { libc::getpwuid(uid) };
+    if pw.is_null() {
+	return None;
+    }
+    let name = unsafe { ffi::CStr::from_ptr((*pw).pw_name) };
+    name.to_str().ok().map(String::from)
+}
+
+fn matches(pattern: &str, item: &str) -> bool {
+    match pattern.strip_suffix('*') {
+	Some(prefix) => item.starts_with(prefix),
+	None => pattern == item,
+    }
+}
+
+// Only root can point vm-init at another policy, so a SUID vm-init cannot be
+// fooled by the environment of the user.
+fn policy_file() -> String {
+    if unsafe { libc::getuid() } == 0 {
+	env::var("VMMAN_POLICY").unwrap_or(String::from(POLICY_FILE))
+    } else {
+	String::from(POLICY_FILE)
+    }
+}
+
+pub fn allowed(kind: &str, uid: u32, item: &str) -> bool {
+    let file = policy_file();
+    let content = match fs::read_to_string(&file) {
+	Ok(c) => c,
+	Err(_) => {
+	    println!("Cannot read policy {}, denying {} {}", file, kind, item);
+	    return false;
+	}
+    };
+    let policy = toml::from_str::<value::Table>(&content).unwrap_or_else(|e| panic!("Cannot parse policy {}: {}", file, e));
+    let rules = match policy.get(kind).and_then(|r| r.as_table()) {
+	Some(r) => r,
+	None => return false,
+    };
+    let name = user_name(uid);
+    rules.iter()
+	.filter(|(user, _)| user.as_str() == "*" || Some(user.as_str()) == name.as_deref())
+	.filter_map(|(_, patterns)| patterns.as_array())
+	.flat_map(|patterns| patterns.iter())
+	.filter_map(|p| p.as_str())
+	.any(|p| matches(p, item))
+}
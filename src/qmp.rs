@@ -0,0 +1,167 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::{path, thread::sleep, time};
+use json::JsonValue;
+
+// QMP serves one client at a time and queues the others, which would wait
+// for the greeting until the first one disconnects.
+const GREETING_TIMEOUT: u64 = 5;
+
+// Minimal QMP client over the unix socket that vm-run passes to qemu.
+pub struct Qmp {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    pub events: Vec<JsonValue>,
+}
+
+impl Qmp {
+    pub fn connect<P: AsRef<path::Path>>(sock: P) -> Result<Qmp, String> {
+	let stream = UnixStream::connect(sock.as_ref())
+	    .map_err(|e| format!("Cannot connect to {}: {}", sock.as_ref().display(), e))?;
+	let writer = stream.try_clone().map_err(|e| e.to_string())?;
+	stream.set_read_timeout(Some(time::Duration::from_secs(GREETING_TIMEOUT))).map_err(|e| e.to_string())?;
+	let mut qmp = Qmp {
+	    reader: BufReader::new(stream),
+	    writer,
+	    events: Vec::new(),
+	};
+	let greeting = qmp.read_message()
+	    .map_err(|e| format!("No QMP greeting on {} ({}), is another client connected?", sock.as_ref().display(), e))?;
+	if greeting["QMP"].is_null() {
+	    return Err(format!("Unexpected QMP greeting {}", greeting.dump()));
+	}
+	qmp.execute("qmp_capabilities", JsonValue::new_object())?;
+	qmp.reader.get_ref().set_read_timeout(None).map_err(|e| e.to_string())?;
+	Ok(qmp)
+    }
+
+    fn read_message(&mut self) -> Result<JsonValue, String> {
+	let mut line = String::new();
+	let n = self.reader.read_line(&mut line).map_err(|e| e.to_string())?;
+	if n == 0 {
+	    return Err(String::from("QMP connection closed"));
+	}
+	json::parse(&line).map_err(|e| format!("Cannot parse QMP message {}: {}", line.trim_end(), e))
+    }
+
+    // Sends a raw QMP message and returns the full response object, keeping
+    // any asynchronous events received in the meantime.
+    pub fn send_raw(&mut self, msg: &str) -> Result<JsonValue, String> {
+	self.writer.write_all(msg.trim_end().as_bytes()).map_err(|e| e.to_string())?;
+	self.writer.write_all(b"\n").map_err(|e| e.to_string())?;
+	self.read_response()
+    }
+
+    fn read_response(&mut self) -> Result<JsonValue, String> {
+	loop {
+	    let m = self.read_message()?;
+	    if m.has_key("event") {
+		self.events.push(m);
+	    } else {
+		return Ok(m);
+	    }
+	}
+    }
+
+    pub fn execute(&mut self, cmd: &str, args: JsonValue) -> Result<JsonValue, String> {
+	let mut msg = json::object!{ "execute": cmd };
+	if !args.is_empty() {
+	    msg["arguments"] = args;
+	}
+	let mut r = self.send_raw(&msg.dump())?;
+	if r.has_key("error") {
+	    return Err(format!("{}: {}", cmd, r["error"]["desc"]));
+	}
+	Ok(r["return"].take())
+    }
+
+    // Hands a file descriptor to qemu under the given name, for commands
+    // such as netdev_add that take an fd name. The descriptor travels as
+    // ancillary data of the getfd command itself.
+    pub fn send_fd(&mut self, name: &str, fd: RawFd) -> Result<(), String> {
+	let msg = format!("{}\n", json::object!{ "execute": "getfd", "arguments": { "fdname": name } }.dump());
+	let r = unsafe {
+	    let mut iov = libc::iovec { iov_base: msg.as_ptr() as *mut libc::c_void, iov_len: msg.len() };
+	    let space = libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) as usize;
+	    let mut control = vec![0u8; space];
+	    let mut hdr: libc::msghdr = std::mem::zeroed();
+	    hdr.msg_iov = &mut iov;
+	    hdr.msg_iovlen = 1;
+	    hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+	    hdr.msg_controllen = space as _;
+	    let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+	    (*cmsg).cmsg_level = libc::SOL_SOCKET;
+	    (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+	    (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+	    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+	    libc::sendmsg(self.writer.as_raw_fd(), &hdr, 0)
+	};
+	if r != msg.len() as isize {
+	    return Err(format!("getfd: {}", std::io::Error::last_os_error()));
+	}
+	let r = self.read_response()?;
+	if r.has_key("error") {
+	    return Err(format!("getfd: {}", r["error"]["desc"]));
+	}
+	Ok(())
+    }
+
+    pub fn hmp(&mut self, cmdline: &str) -> Result<String, String> {
+	let r = self.execute("human-monitor-command", json::object!{ "command-line": cmdline })?;
+	Ok(String::from(r.as_str().unwrap_or("")))
+    }
+
+    // Waits for an asynchronous event, returning its data.
+    pub fn wait_event(&mut self, name: &str, timeout: time::Duration) -> Result<JsonValue, String> {
+	self.reader.get_ref().set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+	let r = loop {
+	    if let Some(idx) = self.events.iter().position(|e| e["event"] == name) {
+		break Ok(self.events.remove(idx)["data"].take());
+	    }
+	    match self.read_message() {
+		Ok(m) => self.events.push(m),
+		Err(e) => break Err(format!("Waiting for {}: {}", name, e)),
+	    }
+	};
+	self.reader.get_ref().set_read_timeout(None).map_err(|e| e.to_string())?;
+	r
+    }
+
+    // Unplugs a device and waits until the guest has let it go.
+    pub fn delete_device(&mut self, id: &str, timeout: time::Duration) -> Result<(), String> {
+	self.execute("device_del", json::object!{ "id": id })?;
+	loop {
+	    let data = self.wait_event("DEVICE_DELETED", timeout)?;
+	    if data["device"] == id {
+		return Ok(());
+	    }
+	}
+    }
+
+    // Waits for a block job to finish, printing its progress.
+    pub fn wait_job(&mut self, job: &str) -> Result<(), String> {
+	loop {
+	    let jobs = self.execute("query-block-jobs", JsonValue::new_object())?;
+	    match jobs.members().find(|j| j["device"] == job) {
+		Some(j) => {
+		    if let Some(pct) = (j["offset"].as_u64().unwrap_or(0) * 100).checked_div(j["len"].as_u64().unwrap_or(0)) {
+			print!("{}: {:3}%\r", job, pct);
+			std::io::stdout().flush().unwrap();
+		    }
+		    sleep(time::Duration::from_millis(500));
+		}
+		None => break,
+	    }
+	}
+	println!();
+	let done = self.events.iter().position(|e| {
+	    (e["event"] == "BLOCK_JOB_COMPLETED" || e["event"] == "BLOCK_JOB_CANCELLED") && e["data"]["device"] == job
+	});
+	match done.map(|idx| self.events.remove(idx)) {
+	    Some(e) if e["event"] == "BLOCK_JOB_CANCELLED" => Err(format!("{} cancelled", job)),
+	    Some(e) if e["data"].has_key("error") => Err(format!("{}: {}", job, e["data"]["error"])),
+	    _ => Ok(()),
+	}
+    }
+}
@@ -0,0 +1,86 @@
+use std::{fs, io::{BufRead, BufReader, Write}, os::unix::io::{AsRawFd, RawFd}};
+use std::process::Command;
+
+// Keys of encrypted images come from
+//
+//   key = "file:/path/to/key"     the whole file
+//   key = "keyring:<description>" a user key in the kernel keyring
+//   key = "prompt"                asked for on the terminal (default)
+//
+// and reach qemu through a pipe, so that they never show up on a command
+// line.
+
+fn prompt(msg: &str) -> Vec<u8> {
+    let tty = fs::OpenOptions::new().read(true).write(true).open("/dev/tty")
+	.expect("Cannot open the terminal to ask for the key");
+    let fd = tty.as_raw_fd();
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    let has_termios = unsafe { libc::tcgetattr(fd, &mut saved) } == 0;
+    if has_termios {
+	let mut noecho = saved;
+	noecho.c_lflag &= !libc::ECHO;
+	unsafe { libc::tcsetattr(fd, libc::TCSANOW, &noecho) };
+    }
+    let mut out = &tty;
+    let _ = write!(out, "{}: ", msg);
+    let _ = out.flush();
+    let mut line = String::new();
+    let r = BufReader::new(&tty).read_line(&mut line);
+    if has_termios {
+	unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+    }
+    let _ = writeln!(out);
+    r.expect("Cannot read the key");
+    line.trim_end_matches('\n').as_bytes().to_vec()
+}
+
+fn keyring(desc: &str) -> Vec<u8> {
+    let p = Command::new("keyctl")
+	.args(["pipe", &format!("%user:{}", desc)])
+	.output()
+	.expect("Cannot run keyctl");
+    if !p.status.success() {
+	panic!("Cannot find key {} in the keyring: {}", desc, String::from_utf8_lossy(&p.stderr).trim());
+    }
+    p.stdout
+}
+
+// `what` names the key in the prompt. New keys are asked for twice.
+pub fn read_key(spec: &str, what: &str, new: bool) -> Vec<u8> {
+    let key = if let Some(f) = spec.strip_prefix("file:") {
+	fs::read(f).unwrap_or_else(|e| panic!("Cannot read key file {}: {}", f, e))
+    } else if let Some(desc) = spec.strip_prefix("keyring:") {
+	keyring(desc)
+    } else if spec == "prompt" {
+	let key = prompt(&format!("{} for {}", if new { "New passphrase" } else { "Passphrase" }, what));
+	if new && prompt(&format!("Repeat the passphrase for {}", what)) != key {
+	    panic!("Passphrases do not match");
+	}
+	key
+    } else {
+	panic!("Invalid key {}, expecting file:<path>, keyring:<description> or prompt", spec);
+    };
+    if key.is_empty() {
+	panic!("Empty key for {}", what);
+    }
+    key
+}
+
+// A pipe holding the key, whose read end is inherited by child processes
+// and can be given to them as /dev/fd/<fd>.
+pub fn key_fd(key: &[u8]) -> RawFd {
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+	panic!("Cannot create a pipe for the key");
+    }
+    let n = unsafe { libc::write(fds[1], key.as_ptr() as *const libc::c_void, key.len()) };
+    unsafe { libc::close(fds[1]) };
+    if n != key.len() as isize {
+	panic!("Cannot pass the key through a pipe");
+    }
+    fds[0]
+}
+
+pub fn close_fd(fd: RawFd) {
+    unsafe { libc::close(fd) };
+}
@@ -0,0 +1,209 @@
+use std::{fs, path};
+use crate::vm::{self, modules::storage::StorageModule};
+use crate::disk::{attached_node, image_args, qemu_img, qemu_img_for, Leases};
+
+fn usage() -> ! {
+    println!("Usage: vm-snapshot create|revert|delete <vm> <tag>");
+    println!("       vm-snapshot list <vm>");
+    std::process::exit(1);
+}
+
+// Writable images snapshots apply to.
+fn snapshot_disks(conf: &vm::VmConf) -> Vec<&StorageModule> {
+    conf.modules.iter()
+	.filter_map(|m| m.as_storage())
+	.filter(|s| !s.read_only() && !s.is_network() && !s.is_block_device())
+	.collect()
+}
+
+// qcow2 images keep their snapshots inside, unless vmman already manages an
+// external chain for them.
+fn is_internal(storage: &StorageModule) -> bool {
+    storage.snapshot_chain().is_empty() && storage.image_format() == "qcow2"
+}
+
+// qemu-img snapshot on the image itself, which needs the key when the
+// qcow2 image is encrypted.
+fn internal_snapshot(storage: &StorageModule, op: &[&str]) {
+    let mut args = vec![String::from("snapshot")];
+    args.extend(op.iter().map(|a| String::from(*a)));
+    args.extend(image_args(storage, &storage.filename, "qcow2"));
+    qemu_img_for(storage, &args.iter().map(|a| a.as_str()).collect::<Vec<_>>());
+}
+
+fn check_tag(tag: &str) {
+    if tag.is_empty() || tag == "chain" || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') || tag.starts_with('.') {
+	panic!("Invalid snapshot tag {}", tag);
+    }
+}
+
+fn create(conf: &vm::VmConf, tag: &str) {
+    let disks = snapshot_disks(conf);
+    for storage in disks.iter() {
+	if storage.snapshot_chain().iter().any(|t| t == tag) {
+	    panic!("storage.{} already has snapshot {}", storage.name, tag);
+	}
+    }
+
+    let qmp = conf.try_qmp();
+    let running = qmp.is_some();
+    // External overlays would hold the new writes in clear.
+    if let Some(storage) = disks.iter().find(|s| s.is_encrypted() && (running || !is_internal(s))) {
+	panic!("storage.{} is encrypted, only internal snapshots of a stopped qcow2 image are supported", storage.name);
+    }
+    match qmp {
+	Some(mut qmp) => {
+	    let nodes = disks.iter().map(|s| attached_node(&mut qmp, s)).collect::<Vec<_>>();
+	    // The overlays would be backed by the throwaway overlays, which are
+	    // deleted when qemu exits.
+	    if disks.iter().zip(nodes.iter()).any(|(s, n)| *n == s.ephemeral_node()) {
+		panic!("{} runs with --ephemeral, its disks cannot be snapshotted", conf.name);
+	    }
+	    // One transaction, so that all disks are snapshotted at the same
+	    // point. The new overlays become the nodes the guest writes to.
+	    let mut actions = json::JsonValue::new_array();
+	    for (storage, node) in disks.iter().zip(nodes.iter()) {
+		fs::create_dir_all(storage.snapshot_dir()).expect("Cannot create the snapshot dir");
+		actions.push(json::object!{
+		    "type": "blockdev-snapshot-sync",
+		    "data": {
+			"node-name": node.as_str(),
+			"snapshot-file": storage.overlay_path(tag).to_str().unwrap(),
+			"snapshot-node-name": format!("snap-{}-{}", storage.name, tag),
+			"format": "qcow2",
+		    },
+		}).unwrap();
+	    }
+	    qmp.execute("transaction", json::object!{ "actions": actions }).expect("Cannot take the snapshot");
+	}
+	None => {
+	    let _leases = Leases::acquire(&disks);
+	    for storage in disks.iter().filter(|s| is_internal(s)) {
+		internal_snapshot(storage, &["-c", tag]);
+		println!("storage.{}: created internal snapshot {}", storage.name, tag);
+	    }
+	    for storage in disks.iter().filter(|s| !is_internal(s)) {
+		let (top, format) = storage.active_image();
+		fs::create_dir_all(storage.snapshot_dir()).expect("Cannot create the snapshot dir");
+		qemu_img(&["create", "-f", "qcow2", "-b", &top, "-F", &format, storage.overlay_path(tag).to_str().unwrap()]);
+	    }
+	}
+    }
+
+    for storage in disks.iter().filter(|s| running || !is_internal(s)) {
+	let mut chain = storage.snapshot_chain();
+	chain.push(String::from(tag));
+	storage.write_snapshot_chain(&chain);
+	println!("storage.{}: created snapshot {}", storage.name, tag);
+    }
+}
+
+fn list(conf: &vm::VmConf) {
+    let running = conf.try_qmp().is_some();
+    for storage in snapshot_disks(conf) {
+	println!("storage.{} ({}):", storage.name, storage.filename);
+	if storage.image_format() == "qcow2" {
+	    internal_snapshot(storage, if running { &["-l", "-U"] } else { &["-l"] });
+	}
+	for tag in storage.snapshot_chain() {
+	    let size = fs::metadata(storage.overlay_path(&tag)).map(|m| m.len()).unwrap_or(0);
+	    println!("  {:20} external, {} bytes written since", tag, size);
+	}
+    }
+}
+
+// Backing image of the overlay of chain[idx], with its format.
+fn overlay_backing(storage: &StorageModule, chain: &[String], idx: usize) -> (String, String) {
+    if idx == 0 {
+	(storage.filename.clone(), storage.image_format())
+    } else {
+	(String::from(storage.overlay_path(&chain[idx - 1]).to_str().unwrap()), String::from("qcow2"))
+    }
+}
+
+fn find_tag(storage: &StorageModule, chain: &[String], tag: &str) -> usize {
+    chain.iter().position(|t| t == tag)
+	.unwrap_or_else(|| panic!("storage.{} has no snapshot {}", storage.name, tag))
+}
+
+fn remove_overlay(p: &path::Path) {
+    fs::remove_file(p).unwrap_or_else(|e| panic!("Cannot remove {}: {}", p.display(), e));
+}
+
+// Throws away everything written after the snapshot.
+fn revert(conf: &vm::VmConf, tag: &str) {
+    let disks = snapshot_disks(conf);
+    let _leases = Leases::acquire(&disks);
+    for storage in disks {
+	if is_internal(storage) {
+	    internal_snapshot(storage, &["-a", tag]);
+	    continue;
+	}
+	let mut chain = storage.snapshot_chain();
+	let idx = find_tag(storage, &chain, tag);
+	for t in chain[idx..].iter() {
+	    remove_overlay(&storage.overlay_path(t));
+	}
+	let (backing, format) = overlay_backing(storage, &chain, idx);
+	qemu_img(&["create", "-f", "qcow2", "-b", &backing, "-F", &format, storage.overlay_path(tag).to_str().unwrap()]);
+	chain.truncate(idx + 1);
+	storage.write_snapshot_chain(&chain);
+	println!("storage.{}: reverted to snapshot {}", storage.name, tag);
+    }
+}
+
+// Merges the overlay of the snapshot into its backing image, so the data
+// stays but the snapshot can no longer be reverted to.
+fn delete(conf: &vm::VmConf, tag: &str) {
+    let disks = snapshot_disks(conf);
+    let _leases = Leases::acquire(&disks);
+    for storage in disks {
+	if is_internal(storage) {
+	    internal_snapshot(storage, &["-d", tag]);
+	    continue;
+	}
+	let mut chain = storage.snapshot_chain();
+	let idx = find_tag(storage, &chain, tag);
+	let overlay = storage.overlay_path(tag);
+	let (backing, format) = overlay_backing(storage, &chain, idx);
+	qemu_img(&["commit", "-f", "qcow2", overlay.to_str().unwrap()]);
+	if let Some(next) = chain.get(idx + 1) {
+	    qemu_img(&["rebase", "-u", "-f", "qcow2", "-b", &backing, "-F", &format,
+		       storage.overlay_path(next).to_str().unwrap()]);
+	}
+	remove_overlay(&overlay);
+	chain.remove(idx);
+	storage.write_snapshot_chain(&chain);
+	if chain.is_empty() {
+	    let _ = fs::remove_dir_all(storage.snapshot_dir());
+	}
+	println!("storage.{}: deleted snapshot {}", storage.name, tag);
+    }
+}
+
+// vm-snapshot create|list|revert|delete <vm> [tag]
+pub fn snapshot(mgr: &mut vm::VmManager, args: &[String]) {
+    let pos = vm::positional_args(args);
+    if pos.len() < 2 {
+	usage();
+    }
+    let conf = mgr.vmconfs.get_mut(&pos[1]).unwrap_or_else(|| panic!("Cannot find machine {}", pos[1]));
+    conf.force_unlock = vm::has_flag(args, "--force-unlock");
+    conf.load();
+    if pos[0] == "list" {
+	list(conf);
+	return;
+    }
+    let tag = pos.get(2).unwrap_or_else(|| usage());
+    check_tag(tag);
+    if pos[0] != "create" && conf.try_qmp().is_some() {
+	panic!("{} is running, stop it before {} a snapshot", conf.name,
+	       if pos[0] == "revert" { "reverting to" } else { "deleting" });
+    }
+    match pos[0].as_str() {
+	"create" => create(conf, tag),
+	"revert" => revert(conf, tag),
+	"delete" => delete(conf, tag),
+	_ => usage(),
+    }
+}
@@ -0,0 +1,1020 @@
+use toml::value;
+use json::JsonValue;
+use std::{fs, path, os::unix::{fs::FileTypeExt, io::RawFd}};
+use std::process::Command;
+use super::{ConfModule, VmEnv, get_string, get_option_string, image, init_perm_checked, lease, restore_perm, secret};
+
+pub const CACHE_MODES: &[&str] = &["none", "writeback", "writethrough", "directsync", "unsafe"];
+pub const AIO_MODES: &[&str] = &["threads", "native", "io_uring"];
+pub const NBD_PORT: u64 = 10809;
+pub const ISCSI_PORT: u64 = 3260;
+pub const BUSES: &[&str] = &["virtio-blk", "virtio-scsi", "nvme", "ahci", "usb-storage"];
+
+fn get_option_int(conf: &value::Table, key: &str) -> Option<u64> {
+    conf.get(key).map(|v| {
+	let i = v.as_integer().unwrap_or_else(|| panic!("Expecting {} as an integer", key));
+	if i < 0 {
+	    panic!("Expecting {} as a non-negative integer", key);
+	}
+	i as u64
+    })
+}
+
+// I/O limits as written in storage and throttle sections, e.g. `iops`,
+// `bps-write` or `iops-read-max`, with the name qemu gives them in a
+// throttle group. Plain `iops`/`bps` limit reads and writes together.
+pub fn throttle_limit_name(key: &str) -> Option<String> {
+    if key == "iops-size" {
+	return Some(String::from(key));
+    }
+    let (kind, rest) = match (key.strip_prefix("iops"), key.strip_prefix("bps")) {
+	(Some(r), _) => ("iops", r),
+	(_, Some(r)) => ("bps", r),
+	_ => return None,
+    };
+    let (dir, burst) = match (rest.strip_prefix("-read"), rest.strip_prefix("-write")) {
+	(Some(b), _) => ("read", b),
+	(_, Some(b)) => ("write", b),
+	_ => ("total", rest),
+    };
+    match burst {
+	"" | "-max" | "-max-length" => Some(format!("{}-{}{}", kind, dir, burst)),
+	_ => None,
+    }
+}
+
+fn throttle_limits(conf: &value::Table) -> Vec<(String, u64)> {
+    conf.keys()
+	.filter_map(|k| throttle_limit_name(k).map(|n| (n, get_option_int(conf, k).unwrap())))
+	.collect()
+}
+
+fn throttle_group_props(id: &str, limits: &[(String, u64)]) -> Props {
+    limits.iter().fold(Props::new().str("qom-type", "throttle-group").str("id", id),
+		       |p, (k, v)| p.int(&format!("limits.{}", k), *v))
+}
+
+fn get_bool(conf: &value::Table, key: &str) -> bool {
+    conf.get(key).map(|v| v.as_bool().unwrap_or_else(|| panic!("Expecting {} as a boolean", key))).unwrap_or(false)
+}
+
+fn check_choice(key: &str, v: &str, choices: &[&str]) {
+    if !choices.contains(&v) {
+	panic!("Unsupported {} {}, expecting one of {}", key, v, choices.join(", "));
+    }
+}
+
+// Where the image lives: a local file (or block device), or a network target.
+#[derive(Clone)]
+pub enum Backend {
+    File,
+    Nbd { host: String, port: u64, export: Option<String> },
+    Iscsi { host: String, port: u64, target: String, lun: u64 },
+}
+
+// Splits "host[:port]/path" from a URL, allowing [v6]:port hosts.
+fn split_url(url: &str, default_port: u64) -> (String, u64, String) {
+    let (hostport, path) = match url.find('/') {
+	Some(idx) => (&url[..idx], &url[idx + 1..]),
+	None => (url, ""),
+    };
+    let (host, port) = if hostport.starts_with('[') {
+	let end = hostport.find(']').unwrap_or_else(|| panic!("Invalid host in {}", url));
+	(&hostport[1..end], hostport[end + 1..].strip_prefix(':'))
+    } else {
+	match hostport.rfind(':') {
+	    Some(idx) => (&hostport[..idx], Some(&hostport[idx + 1..])),
+	    None => (hostport, None),
+	}
+    };
+    let port = port.map(|p| p.parse::<u64>().unwrap_or_else(|e| panic!("Invalid port in {}: {}", url, e))).unwrap_or(default_port);
+    (String::from(host), port, String::from(path))
+}
+
+fn parse_backend(name: &str, conf: &value::Table) -> (Backend, String) {
+    if let Some(protocol) = get_option_string(conf, "protocol") {
+	let host = get_string(conf, "host");
+	let backend = match protocol.as_str() {
+	    "nbd" => Backend::Nbd {
+		host,
+		port: get_option_int(conf, "port").unwrap_or(NBD_PORT),
+		export: get_option_string(conf, "export"),
+	    },
+	    "iscsi" => Backend::Iscsi {
+		host,
+		port: get_option_int(conf, "port").unwrap_or(ISCSI_PORT),
+		target: get_string(conf, "target"),
+		lun: get_option_int(conf, "lun").unwrap_or(0),
+	    },
+	    p => panic!("storage.{}: unsupported protocol {}, expecting nbd or iscsi", name, p),
+	};
+	let url = backend_url(&backend);
+	return (backend, url);
+    }
+
+    let file = get_string(conf, "file");
+    if let Some(rest) = file.strip_prefix("nbd://") {
+	let (host, port, export) = split_url(rest, NBD_PORT);
+	let export = if export.is_empty() { None } else { Some(export) };
+	return (Backend::Nbd { host, port, export }, file);
+    }
+    if let Some(rest) = file.strip_prefix("iscsi://") {
+	let (host, port, path) = split_url(rest, ISCSI_PORT);
+	let (target, lun) = match path.rfind('/') {
+	    Some(idx) => (String::from(&path[..idx]),
+			  path[idx + 1..].parse::<u64>().unwrap_or_else(|e| panic!("Invalid lun in {}: {}", file, e))),
+	    None => (path, 0),
+	};
+	if target.is_empty() {
+	    panic!("storage.{}: expecting iscsi://host[:port]/target[/lun]", name);
+	}
+	return (Backend::Iscsi { host, port, target, lun }, file);
+    }
+    (Backend::File, file)
+}
+
+// IPv6 hosts go in brackets, as split_url expects them.
+fn url_host(host: &str) -> String {
+    if host.contains(':') { format!("[{}]", host) } else { String::from(host) }
+}
+
+fn backend_url(backend: &Backend) -> String {
+    match backend {
+	Backend::File => String::new(),
+	Backend::Nbd { host, port, export } =>
+	    format!("nbd://{}:{}/{}", url_host(host), port, export.as_ref().map(|e| e.as_str()).unwrap_or("")),
+	Backend::Iscsi { host, port, target, lun } => format!("iscsi://{}:{}/{}/{}", url_host(host), port, target, lun),
+    }
+}
+
+#[derive(Clone)]
+pub enum PropValue {
+    Str(String),
+    Bool(bool),
+    Int(u64),
+}
+
+// Options of a -blockdev/-device/-object, which can be rendered both as a
+// command line argument and as QMP arguments for hot-plugging.
+#[derive(Clone, Default)]
+pub struct Props(pub Vec<(String, PropValue)>);
+
+impl Props {
+    pub fn new() -> Props {
+	Props(Vec::new())
+    }
+
+    pub fn str(mut self, key: &str, v: &str) -> Props {
+	self.0.push((String::from(key), PropValue::Str(String::from(v))));
+	self
+    }
+
+    pub fn bool(mut self, key: &str, v: bool) -> Props {
+	self.0.push((String::from(key), PropValue::Bool(v)));
+	self
+    }
+
+    pub fn int(mut self, key: &str, v: u64) -> Props {
+	self.0.push((String::from(key), PropValue::Int(v)));
+	self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&PropValue> {
+	self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    fn render(v: &PropValue) -> String {
+	match v {
+	    PropValue::Str(s) => s.replace(',', ",,"),
+	    PropValue::Bool(b) => String::from(if *b { "on" } else { "off" }),
+	    PropValue::Int(i) => format!("{}", i),
+	}
+    }
+
+    // key=value,... with commas in values doubled, as qemu expects.
+    pub fn to_arg(&self) -> String {
+	self.0.iter().map(|(k, v)| format!("{}={}", k, Props::render(v))).collect::<Vec<_>>().join(",")
+    }
+
+    // Same as to_arg(), with the value of `implied` first and without its
+    // key, e.g. the driver of -device or the type of -object.
+    pub fn to_arg_implied(&self, implied: &str) -> String {
+	let mut parts = self.get(implied).map(|v| vec![Props::render(v)]).unwrap_or_default();
+	parts.extend(self.0.iter().filter(|(k, _)| k != implied).map(|(k, v)| format!("{}={}", k, Props::render(v))));
+	parts.join(",")
+    }
+
+    // QMP arguments, with dotted keys turned into nested objects.
+    pub fn to_json(&self) -> JsonValue {
+	let mut obj = JsonValue::new_object();
+	for (k, v) in self.0.iter() {
+	    let mut cur = &mut obj;
+	    let parts = k.split('.').collect::<Vec<_>>();
+	    for p in parts[..parts.len() - 1].iter() {
+		if !cur.has_key(p) {
+		    cur[*p] = JsonValue::new_object();
+		}
+		cur = &mut cur[*p];
+	    }
+	    cur[parts[parts.len() - 1]] = match v {
+		PropValue::Str(s) => JsonValue::from(s.as_str()),
+		PropValue::Bool(b) => JsonValue::from(*b),
+		PropValue::Int(i) => JsonValue::from(*i),
+	    };
+	}
+	obj
+    }
+}
+
+pub struct StorageModule {
+    pub name: String,
+    bus: String,
+    serial: Option<String>,
+    wwn: Option<String>,
+    logical_block_size: Option<u64>,
+    bootindex: Option<u64>,
+    pub filename: String,
+    pub backend: Backend,
+    pub format: Option<String>,
+    pub media: Option<String>,
+    cache: String,
+    aio: String,
+    discard: Option<String>,
+    detect_zeroes: Option<String>,
+    read_only: bool,
+    iothread: bool,
+    ephemeral: Option<path::PathBuf>,
+    pub local_cache: Option<path::PathBuf>,
+    encrypt: Option<String>,
+    throttle_limits: Vec<(String, u64)>,
+    throttle_group: Option<String>,
+    key: String,
+    key_data: Option<Vec<u8>>,
+    secret_fd: Option<RawFd>,
+    vm: String,
+    force_unlock: bool,
+    migrate_from: Option<(String, u32)>,
+}
+
+impl StorageModule {
+    pub fn new(name: &str, conf: &value::Table, vmenv: &VmEnv) -> StorageModule {
+	let format = get_option_string(conf, "format");
+	if let Some(f) = format.as_ref() {
+	    check_choice("storage format", f, image::FORMATS);
+	}
+	// "local:<dir>" keeps a copy-on-read overlay of the image in a local
+	// dir, with the default cache mode.
+	let (cache, local_cache) = match get_option_string(conf, "cache") {
+	    Some(c) if c.starts_with("local:") => {
+		let dir = path::Path::new(&c["local:".len()..]);
+		(String::from("none"), Some(dir.join(format!("{}-{}.qcow2", vmenv.name, name))))
+	    }
+	    c => (c.unwrap_or(String::from("none")), None),
+	};
+	check_choice("cache mode", &cache, CACHE_MODES);
+	let aio = get_option_string(conf, "aio").unwrap_or(String::from("native"));
+	check_choice("aio backend", &aio, AIO_MODES);
+	let discard = get_option_string(conf, "discard");
+	if let Some(d) = discard.as_ref() {
+	    check_choice("discard mode", d, &["ignore", "unmap"]);
+	}
+	// `driver` is the older name of `bus`, where "ide" means the IDE/AHCI
+	// controller built into the machine.
+	let bus = match (get_option_string(conf, "bus"), get_option_string(conf, "driver")) {
+	    (Some(b), _) => {
+		check_choice("storage bus", &b, BUSES);
+		b
+	    }
+	    (None, Some(d)) if d == "virtio" => String::from("virtio-blk"),
+	    (None, Some(d)) if d == "ide" => d,
+	    (None, Some(d)) => panic!("storage.{}: unsupported driver {}, consider using 'bus'", name, d),
+	    (None, None) => panic!("storage.{}: expecting bus as a string", name),
+	};
+	let encrypt = get_option_string(conf, "encrypt");
+	if let Some(e) = encrypt.as_ref() {
+	    check_choice("encryption", e, &["luks"]);
+	}
+	let detect_zeroes = get_option_string(conf, "detect-zeroes");
+	if let Some(d) = detect_zeroes.as_ref() {
+	    check_choice("detect-zeroes mode", d, &["off", "on", "unmap"]);
+	}
+
+	let (backend, filename) = parse_backend(name, conf);
+	let mut m = StorageModule {
+	    name: String::from(name),
+	    bus,
+	    serial: get_option_string(conf, "serial"),
+	    wwn: get_option_string(conf, "wwn"),
+	    logical_block_size: get_option_int(conf, "logical_block_size"),
+	    bootindex: get_option_int(conf, "bootindex"),
+	    filename,
+	    backend,
+	    format,
+	    media: get_option_string(conf, "media"),
+	    cache,
+	    aio,
+	    discard,
+	    detect_zeroes,
+	    read_only: get_bool(conf, "read-only"),
+	    iothread: get_bool(conf, "iothread"),
+	    ephemeral: None,
+	    local_cache,
+	    encrypt,
+	    throttle_limits: throttle_limits(conf),
+	    throttle_group: get_option_string(conf, "throttle-group"),
+	    key: get_option_string(conf, "key").unwrap_or(String::from("prompt")),
+	    key_data: None,
+	    secret_fd: None,
+	    vm: vmenv.name.clone(),
+	    force_unlock: vmenv.force_unlock,
+	    migrate_from: vmenv.migrate_from.clone(),
+	};
+	if m.aio == "native" && !m.cache_direct() {
+	    panic!("storage.{}: aio = \"native\" needs cache = \"none\" or \"directsync\"", name);
+	}
+	if m.iothread && m.bus != "virtio-blk" && m.bus != "virtio-scsi" {
+	    panic!("storage.{}: iothread is only supported on virtio-blk and virtio-scsi", name);
+	}
+	if m.is_cdrom() && (m.bus == "virtio-blk" || m.bus == "nvme") {
+	    panic!("storage.{}: {} does not support cdrom media", name, m.bus);
+	}
+	if m.wwn.is_some() && (m.bus == "virtio-blk" || m.bus == "nvme" || m.bus == "usb-storage") {
+	    panic!("storage.{}: {} does not support wwn", name, m.bus);
+	}
+	if m.local_cache.is_some() && (m.read_only() || m.is_block_device()) {
+	    panic!("storage.{}: a local cache needs a writable image on shared storage", name);
+	}
+	if !m.throttle_limits.is_empty() && m.throttle_group.is_some() {
+	    panic!("storage.{}: set the limits either in the section or in its throttle group", name);
+	}
+	if m.local_cache.is_some() && m.is_encrypted() {
+	    panic!("storage.{}: a local cache would keep the data of an encrypted image in clear", name);
+	}
+	// Throwaway runs write to an overlay in the runtime dir instead.
+	if vmenv.ephemeral && !m.read_only() {
+	    m.ephemeral = Some(vmenv.rundir.join(format!("ephemeral-{}.qcow2", name)));
+	}
+	m
+    }
+
+    pub fn is_cdrom(&self) -> bool {
+	self.media.as_ref().map(|m| m == "cdrom").unwrap_or(false)
+    }
+
+    pub fn is_network(&self) -> bool {
+	!matches!(self.backend, Backend::File)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+	self.encrypt.is_some()
+    }
+
+    pub fn read_key(&self, new: bool) -> Vec<u8> {
+	secret::read_key(&self.key, &format!("storage.{} of {}", self.name, self.vm), new)
+    }
+
+    pub fn secret_id(&self) -> String {
+	format!("sec-{}", self.name)
+    }
+
+    pub fn is_block_device(&self) -> bool {
+	!self.is_network() && fs::metadata(&self.filename).map(|m| m.file_type().is_block_device()).unwrap_or(false)
+    }
+
+    // The configured format, or the one detected from the image header.
+    // Host block devices and network targets are never probed and default
+    // to raw.
+    pub fn image_format(&self) -> String {
+	match self.format.as_ref() {
+	    Some(f) => f.clone(),
+	    None if self.is_network() || self.is_block_device() => String::from("raw"),
+	    None => image::detect_format(&self.filename).unwrap_or_else(|e| panic!("{}", e)),
+	}
+    }
+
+    // External snapshots taken by vm-snapshot live next to the image, with
+    // the tags listed oldest first in the chain file.
+    pub fn snapshot_dir(&self) -> path::PathBuf {
+	path::PathBuf::from(format!("{}.snapshots", self.filename))
+    }
+
+    pub fn overlay_path(&self, tag: &str) -> path::PathBuf {
+	self.snapshot_dir().join(format!("{}.qcow2", tag))
+    }
+
+    pub fn snapshot_chain(&self) -> Vec<String> {
+	fs::read_to_string(self.snapshot_dir().join("chain")).unwrap_or_default()
+	    .lines().filter(|l| !l.is_empty()).map(String::from).collect()
+    }
+
+    pub fn write_snapshot_chain(&self, chain: &[String]) {
+	let p = self.snapshot_dir().join("chain");
+	fs::create_dir_all(self.snapshot_dir()).unwrap_or_else(|e| panic!("Cannot create {}: {}", self.snapshot_dir().display(), e));
+	fs::write(&p, chain.iter().map(|t| t.clone() + "\n").collect::<String>())
+	    .unwrap_or_else(|e| panic!("Cannot write {}: {}", p.display(), e));
+    }
+
+    // The image qemu writes to and its format: the newest snapshot overlay,
+    // or the configured file.
+    pub fn active_image(&self) -> (String, String) {
+	match self.snapshot_chain().last() {
+	    Some(tag) => (String::from(self.overlay_path(tag).to_str().unwrap()), String::from("qcow2")),
+	    None => (self.filename.clone(), self.image_format()),
+	}
+    }
+
+    // Images on shared storage that this VM writes to are leased, so that
+    // no other host starts the VM at the same time.
+    pub fn lease_path(&self) -> Option<path::PathBuf> {
+	if self.is_network() || self.is_cdrom() || self.read_only() || self.ephemeral.is_some() || self.is_block_device() {
+	    None
+	} else {
+	    Some(path::PathBuf::from(format!("{}.lease", self.filename)))
+	}
+    }
+
+    pub fn acquire_lease(&self, pid: u32) {
+	if let Some(p) = self.lease_path() {
+	    lease::acquire(&p, &self.vm, pid, self.force_unlock, self.migrate_from.as_ref());
+	}
+    }
+
+    // Takes the lease back for the running qemu `pid` of this host, e.g.
+    // after a failed migration whose destination released it.
+    pub fn restore_lease(&self, pid: u32) {
+	if let Some(p) = self.lease_path() {
+	    lease::update(&p, &self.vm, pid);
+	}
+    }
+
+    pub fn release_lease(&self) {
+	if let Some(p) = self.lease_path() {
+	    lease::release(&p);
+	}
+    }
+
+    fn cache_direct(&self) -> bool {
+	self.cache == "none" || self.cache == "directsync"
+    }
+
+    fn write_cache(&self) -> bool {
+	self.cache != "writethrough" && self.cache != "directsync"
+    }
+
+    pub fn file_node(&self) -> String {
+	format!("file-{}", self.name)
+    }
+
+    // Limits apply to the I/O on the image itself, which is what loads
+    // the shared storage; overlays in local dirs are not throttled.
+    fn throttle_node(&self) -> Option<String> {
+	self.throttle_group_id().map(|_| format!("thr-{}", self.name))
+    }
+
+    // The throttle group of the disk, either its own or a named one shared
+    // with other disks.
+    pub fn throttle_group_id(&self) -> Option<String> {
+	match self.throttle_group.as_ref() {
+	    Some(g) => Some(format!("tg-{}", g)),
+	    None if !self.throttle_limits.is_empty() => Some(format!("tg-storage-{}", self.name)),
+	    None => None,
+	}
+    }
+
+    pub fn format_node(&self) -> String {
+	format!("fmt-{}", self.name)
+    }
+
+    // The image, seen through the local cache if there is one.
+    fn cached_node(&self) -> String {
+	if self.local_cache.is_some() {
+	    format!("cor-{}", self.name)
+	} else {
+	    self.format_node()
+	}
+    }
+
+    // The throwaway overlay of a VM started with --ephemeral.
+    pub fn ephemeral_node(&self) -> String {
+	format!("eph-{}", self.name)
+    }
+
+    // The node the guest device is attached to.
+    pub fn top_node(&self) -> String {
+	if self.ephemeral.is_some() {
+	    self.ephemeral_node()
+	} else {
+	    self.cached_node()
+	}
+    }
+
+    // The block nodes of the disk, from the one the guest device is
+    // attached to down to the image.
+    pub fn node_names(&self) -> Vec<String> {
+	let mut nodes = Vec::new();
+	if self.ephemeral.is_some() {
+	    nodes.push(self.top_node());
+	    nodes.push(format!("eph-file-{}", self.name));
+	}
+	if self.local_cache.is_some() {
+	    nodes.push(self.cached_node());
+	    nodes.push(format!("cache-{}", self.name));
+	    nodes.push(format!("cache-file-{}", self.name));
+	}
+	nodes.push(self.format_node());
+	nodes.extend(self.throttle_node());
+	nodes.push(self.file_node());
+	nodes
+    }
+
+    pub fn device_id(&self) -> String {
+	format!("dev-{}", self.name)
+    }
+
+    pub fn iothread_id(&self) -> String {
+	format!("iothread-{}", self.name)
+    }
+
+    pub fn read_only(&self) -> bool {
+	self.read_only || self.is_cdrom()
+    }
+
+    // The image itself is only written to when the guest writes straight
+    // into it.
+    fn common_props(&self, props: Props) -> Props {
+	let read_only = self.read_only() || self.ephemeral.is_some() || self.local_cache.is_some();
+	self.cache_props(props, read_only)
+    }
+
+    fn cache_props(&self, props: Props, read_only: bool) -> Props {
+	let mut props = props
+	    .bool("cache.direct", self.cache_direct())
+	    .bool("cache.no-flush", self.cache == "unsafe")
+	    .bool("read-only", read_only);
+	if let Some(d) = self.discard.as_ref() {
+	    props = props.str("discard", d);
+	}
+	props
+    }
+
+    pub fn file_props(&self) -> Props {
+	let props = match &self.backend {
+	    Backend::File => Props::new()
+		.str("driver", if self.is_block_device() { "host_device" } else { "file" })
+		.str("node-name", &self.file_node())
+		.str("filename", &self.active_image().0)
+		.str("aio", &self.aio),
+	    Backend::Nbd { host, port, export } => {
+		let p = Props::new()
+		    .str("driver", "nbd")
+		    .str("node-name", &self.file_node())
+		    .str("server.type", "inet")
+		    .str("server.host", host)
+		    .str("server.port", &port.to_string());
+		match export {
+		    Some(e) => p.str("export", e),
+		    None => p,
+		}
+	    }
+	    Backend::Iscsi { host, port, target, lun } => Props::new()
+		.str("driver", "iscsi")
+		.str("node-name", &self.file_node())
+		.str("transport", "tcp")
+		.str("portal", &if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) })
+		.str("target", target)
+		.int("lun", *lun),
+	};
+	self.common_props(props)
+    }
+
+    // Encrypted raw images are LUKS containers, qcow2 images encrypt their
+    // clusters with LUKS.
+    pub fn image_driver(&self, format: &str) -> String {
+	match (self.is_encrypted(), format) {
+	    (true, "raw") => String::from("luks"),
+	    (true, "qcow2") | (false, _) => String::from(format),
+	    (true, f) => panic!("storage.{}: {} images cannot be encrypted", self.name, f),
+	}
+    }
+
+    // The key options of an image of this disk, also for copies of it that
+    // are encrypted with the same key.
+    pub fn key_props(&self, props: Props, format: &str) -> Props {
+	if !self.is_encrypted() {
+	    return props;
+	}
+	match self.image_driver(format).as_str() {
+	    "luks" => props.str("key-secret", &self.secret_id()),
+	    _ => props.str("encrypt.format", "luks").str("encrypt.key-secret", &self.secret_id()),
+	}
+    }
+
+    pub fn format_props(&self, format: &str) -> Props {
+	let props = Props::new()
+	    .str("driver", &self.image_driver(format))
+	    .str("node-name", &self.format_node())
+	    .str("file", &self.throttle_node().unwrap_or(self.file_node()));
+	let mut props = self.key_props(props, format);
+	if let Some(d) = self.detect_zeroes.as_ref() {
+	    props = props.str("detect-zeroes", d);
+	}
+	self.common_props(props)
+    }
+
+    pub fn controller_id(&self) -> String {
+	let prefix = match self.bus.as_str() {
+	    "virtio-scsi" => "scsi",
+	    "usb-storage" => "xhci",
+	    b => b,
+	};
+	format!("{}-{}", prefix, self.name)
+    }
+
+    // Every disk gets its own controller, so that controllers never need to
+    // be shared between sections.
+    pub fn controller_props(&self) -> Option<Props> {
+	let props = match self.bus.as_str() {
+	    "virtio-scsi" => {
+		let p = Props::new().str("driver", "virtio-scsi-pci");
+		if self.iothread { p.str("iothread", &self.iothread_id()) } else { p }
+	    }
+	    "nvme" => Props::new().str("driver", "nvme")
+		.str("serial", self.serial.as_ref().unwrap_or(&self.name)),
+	    "ahci" => Props::new().str("driver", "ahci"),
+	    "usb-storage" => Props::new().str("driver", "qemu-xhci"),
+	    _ => return None,
+	};
+	Some(props.str("id", &self.controller_id()))
+    }
+
+    pub fn device_props(&self) -> Props {
+	let controller = self.controller_id();
+	let cdrom = self.is_cdrom();
+	let mut props = match self.bus.as_str() {
+	    "virtio-blk" => Props::new().str("driver", "virtio-blk-pci"),
+	    "virtio-scsi" => Props::new().str("driver", if cdrom { "scsi-cd" } else { "scsi-hd" })
+		.str("bus", &format!("{}.0", controller)),
+	    "nvme" => Props::new().str("driver", "nvme-ns").str("bus", &controller),
+	    "ahci" => Props::new().str("driver", if cdrom { "ide-cd" } else { "ide-hd" })
+		.str("bus", &format!("{}.0", controller)),
+	    "usb-storage" => Props::new().str("driver", "usb-storage").str("bus", &format!("{}.0", controller)),
+	    _ => Props::new().str("driver", if cdrom { "ide-cd" } else { "ide-hd" }),
+	};
+	props = props
+	    .str("id", &self.device_id())
+	    .str("drive", &self.top_node());
+	if let Some(serial) = self.serial.as_ref() {
+	    if self.bus != "nvme" {
+		props = props.str("serial", serial);
+	    }
+	}
+	if let Some(wwn) = self.wwn.as_ref() {
+	    props = props.str("wwn", wwn);
+	}
+	if let Some(size) = self.logical_block_size {
+	    props = props.int("logical_block_size", size).int("physical_block_size", size);
+	}
+	if let Some(idx) = self.bootindex {
+	    props = props.int("bootindex", idx);
+	}
+	if !self.write_cache() {
+	    props = props.bool("write-cache", false);
+	}
+	if self.iothread && self.bus == "virtio-blk" {
+	    props = props.str("iothread", &self.iothread_id());
+	}
+	props
+    }
+}
+
+impl StorageModule {
+    // A fresh qcow2 overlay on top of the read-only image, which is removed
+    // by cleanup() when qemu exits. The overlay of an encrypted disk is
+    // encrypted with its key, and its backing is opened with the key, so
+    // that a LUKS container is sized by its payload.
+    fn ephemeral_args(&self, overlay: &path::Path, filename: &str, format: &str) -> Vec<String> {
+	let _ = fs::remove_file(overlay);
+	let mut args = vec![String::from("create"), String::from("-q")];
+	let key_fd = self.key_data.as_ref().map(|k| secret::key_fd(k));
+	let backing = match key_fd {
+	    Some(fd) => {
+		args.push(String::from("--object"));
+		args.push(format!("secret,id={},file=/dev/fd/{},format=raw", self.secret_id(), fd));
+		args.push(String::from("-o"));
+		args.push(self.key_props(Props::new(), "qcow2").to_arg());
+		let props = Props::new().str("driver", &self.image_driver(format)).str("file.filename", filename);
+		format!("json:{}", self.key_props(props, format).to_json().dump())
+	    }
+	    None => String::from(filename),
+	};
+	args.extend(vec![String::from("-f"), String::from("qcow2"), String::from("-b"), backing,
+			 String::from("-F"), self.image_driver(format), String::from(overlay.to_str().unwrap())]);
+	let p = Command::new("qemu-img").args(&args).output();
+	if let Some(fd) = key_fd {
+	    secret::close_fd(fd);
+	}
+	let p = p.expect("Cannot run qemu-img to create the ephemeral overlay");
+	if !p.status.success() {
+	    panic!("{}", String::from_utf8_lossy(&p.stderr));
+	}
+	let file_node = format!("eph-file-{}", self.name);
+	let props = Props::new()
+	    .str("driver", "qcow2")
+	    .str("node-name", &self.top_node())
+	    .str("file", &file_node)
+	    .str("backing", &self.cached_node());
+	vec![String::from("-blockdev"),
+	     Props::new()
+		 .str("driver", "file")
+		 .str("node-name", &file_node)
+		 .str("filename", overlay.to_str().unwrap())
+		 .to_arg(),
+	     String::from("-blockdev"),
+	     self.key_props(props, "qcow2").to_arg()]
+    }
+
+    // The local overlay takes the writes of the guest and, through the
+    // copy-on-read filter, everything read from the image. It lives across
+    // runs until vm-disk commits or discards it.
+    fn local_cache_args(&self, overlay: &path::Path, filename: &str, format: &str) -> Vec<String> {
+	let backing = if self.is_network() { self.filename.clone() } else { filename.to_string() };
+	if overlay.exists() {
+	    if !self.is_network() {
+		let current = image::backing_file(overlay, "qcow2").unwrap_or_else(|e| panic!("{}", e)).map(|(b, _)| b);
+		if current.as_ref().map(|b| fs::canonicalize(b).ok()) != Some(fs::canonicalize(&backing).ok()) {
+		    panic!("storage.{}: the local cache {} is not backed by {}, commit or discard it first",
+			   self.name, overlay.display(), backing);
+		}
+	    }
+	} else {
+	    if let Some(dir) = overlay.parent() {
+		fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Cannot create {}: {}", dir.display(), e));
+	    }
+	    let p = Command::new("qemu-img")
+		.args(["create", "-q", "-f", "qcow2", "-b", &backing, "-F", format, overlay.to_str().unwrap()])
+		.output()
+		.expect("Cannot run qemu-img to create the local cache");
+	    if !p.status.success() {
+		panic!("{}", String::from_utf8_lossy(&p.stderr));
+	    }
+	}
+	let file_node = format!("cache-file-{}", self.name);
+	let cache_node = format!("cache-{}", self.name);
+	vec![String::from("-blockdev"),
+	     self.cache_props(Props::new()
+			      .str("driver", "file")
+			      .str("node-name", &file_node)
+			      .str("filename", overlay.to_str().unwrap())
+			      .str("aio", &self.aio), false)
+		 .to_arg(),
+	     String::from("-blockdev"),
+	     self.cache_props(Props::new()
+			      .str("driver", "qcow2")
+			      .str("node-name", &cache_node)
+			      .str("file", &file_node)
+			      .str("backing", &self.format_node()), false)
+		 .to_arg(),
+	     String::from("-blockdev"),
+	     Props::new()
+		 .str("driver", "copy-on-read")
+		 .str("node-name", &self.cached_node())
+		 .str("file", &cache_node)
+		 .to_arg()]
+    }
+}
+
+impl ConfModule for StorageModule {
+    fn init(&self, uid: u32, gid: u32) {
+	if self.is_block_device() {
+	    init_perm_checked("block", &self.filename, uid, gid);
+	}
+    }
+
+    fn deinit(&self, uid: u32, _gid: u32) {
+	if self.is_block_device() {
+	    restore_perm("block", &self.filename, &self.filename, uid);
+	}
+    }
+
+    fn startup_args(&mut self) -> Vec<String> {
+	let (filename, format) = if self.is_network() {
+	    (self.filename.clone(), self.image_format())
+	} else {
+	    self.active_image()
+	};
+	let chain = if self.is_network() {
+	    vec![]
+	} else {
+	    image::validate_chain(&filename, &format).unwrap_or_else(|e| panic!("{}", e))
+	};
+	self.acquire_lease(std::process::id());
+	if chain.len() > 1 {
+	    println!("{} is backed by {}", &filename,
+		     chain[1..].iter().map(|(p, _)| p.to_str().unwrap()).collect::<Vec<_>>().join(" -> "));
+	}
+
+	let mut args = Vec::new();
+	if self.is_encrypted() {
+	    // Asked for once, so that a supervised VM restarts on its own.
+	    if self.key_data.is_none() {
+		self.key_data = Some(self.read_key(false));
+	    }
+	    let fd = secret::key_fd(self.key_data.as_ref().unwrap());
+	    self.secret_fd = Some(fd);
+	    args.push(String::from("-object"));
+	    args.push(format!("secret,id={},file=/dev/fd/{},format=raw", self.secret_id(), fd));
+	}
+	if self.iothread {
+	    args.push(String::from("-object"));
+	    args.push(format!("iothread,id={}", self.iothread_id()));
+	}
+	args.push(String::from("-blockdev"));
+	args.push(self.file_props().to_arg());
+	if let Some(node) = self.throttle_node() {
+	    let group = self.throttle_group_id().unwrap();
+	    if self.throttle_group.is_none() {
+		args.push(String::from("-object"));
+		args.push(throttle_group_props(&group, &self.throttle_limits).to_arg_implied("qom-type"));
+	    }
+	    args.push(String::from("-blockdev"));
+	    args.push(Props::new()
+		      .str("driver", "throttle")
+		      .str("node-name", &node)
+		      .str("throttle-group", &group)
+		      .str("file", &self.file_node())
+		      .to_arg());
+	}
+	args.push(String::from("-blockdev"));
+	args.push(self.format_props(&format).to_arg());
+	if let Some(overlay) = self.local_cache.as_ref() {
+	    args.extend(self.local_cache_args(overlay, &filename, &format));
+	}
+	if let Some(overlay) = self.ephemeral.as_ref() {
+	    args.extend(self.ephemeral_args(overlay, &filename, &format));
+	}
+	if let Some(controller) = self.controller_props() {
+	    args.push(String::from("-device"));
+	    args.push(controller.to_arg_implied("driver"));
+	}
+	args.push(String::from("-device"));
+	args.push(self.device_props().to_arg_implied("driver"));
+	args
+    }
+
+    fn post_startup(&mut self, pid: u32) {
+	if let Some(fd) = self.secret_fd.take() {
+	    secret::close_fd(fd);
+	}
+	if let Some(p) = self.lease_path() {
+	    lease::update(&p, &self.vm, pid);
+	}
+    }
+
+    fn cleanup(&mut self) {
+	self.release_lease();
+	if let Some(overlay) = self.ephemeral.as_ref() {
+	    println!("Removing ephemeral overlay {}", overlay.display());
+	    let _ = fs::remove_file(overlay);
+	}
+    }
+
+    fn as_storage(&self) -> Option<&StorageModule> {
+	Some(self)
+    }
+}
+
+// A named throttle group, whose limits are shared by all the disks that
+// refer to it with `throttle-group`.
+pub struct ThrottleModule {
+    name: String,
+    limits: Vec<(String, u64)>,
+}
+
+impl ThrottleModule {
+    pub fn new(name: &str, conf: &value::Table) -> ThrottleModule {
+	ThrottleModule {
+	    name: String::from(name),
+	    limits: throttle_limits(conf),
+	}
+    }
+}
+
+impl ConfModule for ThrottleModule {
+    fn startup_args(&mut self) -> Vec<String> {
+	vec![String::from("-object"),
+	     throttle_group_props(&format!("tg-{}", self.name), &self.limits).to_arg_implied("qom-type")]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(conf: &str) -> (Backend, String) {
+	parse_backend("main", &toml::from_str::<value::Table>(conf).unwrap())
+    }
+
+    #[test]
+    fn split_url_host_port_path() {
+	assert_eq!(split_url("host:1234/disk", NBD_PORT), (String::from("host"), 1234, String::from("disk")));
+	assert_eq!(split_url("host/disk", NBD_PORT), (String::from("host"), NBD_PORT, String::from("disk")));
+	assert_eq!(split_url("host", ISCSI_PORT), (String::from("host"), ISCSI_PORT, String::new()));
+	assert_eq!(split_url("host/iqn.x/1", ISCSI_PORT), (String::from("host"), ISCSI_PORT, String::from("iqn.x/1")));
+    }
+
+    #[test]
+    fn split_url_ipv6() {
+	assert_eq!(split_url("[::1]:10810/disk", NBD_PORT), (String::from("::1"), 10810, String::from("disk")));
+	assert_eq!(split_url("[fe80::1]/disk", NBD_PORT), (String::from("fe80::1"), NBD_PORT, String::from("disk")));
+	assert_eq!(split_url("[::1]", NBD_PORT), (String::from("::1"), NBD_PORT, String::new()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid host")]
+    fn split_url_unclosed_ipv6() {
+	split_url("[::1:10809/disk", NBD_PORT);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid port")]
+    fn split_url_invalid_port() {
+	split_url("host:nbd/disk", NBD_PORT);
+    }
+
+    #[test]
+    fn parse_backend_file() {
+	let (b, file) = backend("file = \"/vm/main.img\"");
+	assert!(matches!(b, Backend::File));
+	assert_eq!(file, "/vm/main.img");
+    }
+
+    #[test]
+    fn parse_backend_nbd_url() {
+	match backend("file = \"nbd://[::1]:10810/exp\"").0 {
+	    Backend::Nbd { host, port, export } => {
+		assert_eq!((host.as_str(), port, export.as_deref()), ("::1", 10810, Some("exp")));
+	    }
+	    _ => panic!("Expecting nbd"),
+	}
+	match backend("file = \"nbd://host\"").0 {
+	    Backend::Nbd { host, port, export } => assert_eq!((host.as_str(), port, export), ("host", NBD_PORT, None)),
+	    _ => panic!("Expecting nbd"),
+	}
+    }
+
+    #[test]
+    fn parse_backend_iscsi_url() {
+	let url = "iscsi://san:3261/iqn.2001-04.com.example:storage/2";
+	match backend(&format!("file = \"{}\"", url)) {
+	    (Backend::Iscsi { host, port, target, lun }, file) => {
+		assert_eq!((host.as_str(), port, target.as_str(), lun), ("san", 3261, "iqn.2001-04.com.example:storage", 2));
+		assert_eq!(file, url);
+	    }
+	    _ => panic!("Expecting iscsi"),
+	}
+	match backend("file = \"iscsi://[fd00::2]/iqn.2001-04.com.example:storage\"").0 {
+	    Backend::Iscsi { host, port, target, lun } => {
+		assert_eq!((host.as_str(), port, target.as_str(), lun), ("fd00::2", ISCSI_PORT, "iqn.2001-04.com.example:storage", 0));
+	    }
+	    _ => panic!("Expecting iscsi"),
+	}
+    }
+
+    #[test]
+    #[should_panic(expected = "expecting iscsi://host[:port]/target[/lun]")]
+    fn parse_backend_iscsi_without_target() {
+	backend("file = \"iscsi://san\"");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid lun")]
+    fn parse_backend_iscsi_invalid_lun() {
+	backend("file = \"iscsi://san/iqn.x/lun\"");
+    }
+
+    #[test]
+    fn parse_backend_protocol_keys() {
+	let (b, url) = backend("protocol = \"iscsi\"\nhost = \"san\"\ntarget = \"iqn.x\"\nlun = 3");
+	assert!(matches!(b, Backend::Iscsi { port: ISCSI_PORT, lun: 3, .. }));
+	assert_eq!(url, "iscsi://san:3260/iqn.x/3");
+	let (_, url) = backend("protocol = \"nbd\"\nhost = \"::1\"\nexport = \"exp\"");
+	assert_eq!(url, "nbd://[::1]:10809/exp");
+    }
+
+    #[test]
+    fn throttle_limit_names() {
+	for (key, name) in [("iops", "iops-total"), ("bps", "bps-total"), ("iops-read", "iops-read"),
+			    ("bps-write", "bps-write"), ("iops-max", "iops-total-max"),
+			    ("bps-read-max", "bps-read-max"), ("iops-write-max-length", "iops-write-max-length"),
+			    ("iops-size", "iops-size")].iter() {
+	    assert_eq!(throttle_limit_name(key).as_deref(), Some(*name), "{}", key);
+	}
+    }
+
+    #[test]
+    fn throttle_limit_names_other_keys() {
+	for key in ["file", "iops-total", "bps-size", "iops-read-min", "iopsx", "bps-max-size"].iter() {
+	    assert_eq!(throttle_limit_name(key), None, "{}", key);
+	}
+    }
+}
@@ -0,0 +1,129 @@
+use std::{env, fs, path};
+use crate::vm;
+
+// The vm-* commands are links installed next to each other, so look for the
+// one we were invoked as.
+fn bin_dir(argv0: &str) -> path::PathBuf {
+    let p = path::Path::new(argv0);
+    if p.components().count() > 1 {
+	let dir = p.parent().expect("Cannot locate the vmman binary directory");
+	return env::current_dir().expect("Cannot get current dir").join(dir);
+    }
+    env::split_paths(&env::var_os("PATH").expect("Cannot get PATH"))
+	.find(|d| d.join(argv0).exists())
+	.expect("Cannot locate the vmman binary directory")
+}
+
+pub fn init_unit_name(user: &str, name: &str) -> String {
+    format!("vmman-init-{}-{}.service", user, name)
+}
+
+// Root oneshot unit: vm-init needs the network and hugepages before it can
+// create taps and hand out resources.
+fn init_unit(confdir: &str, bindir: &path::Path, user: &str, name: &str) -> String {
+    [
+	"[Unit]".to_string(),
+	format!("Description=vmman resources for {}'s VM {}", user, name),
+	"Wants=network-online.target".to_string(),
+	"After=network-online.target".to_string(),
+	"RequiresMountsFor=/dev/hugepages".to_string(),
+	"".to_string(),
+	"[Service]".to_string(),
+	"Type=oneshot".to_string(),
+	"RemainAfterExit=yes".to_string(),
+	format!("Environment=VMCONF_DIR={}", confdir),
+	format!("ExecStart={} {}", bindir.join("vm-init").display(), name),
+	"".to_string(),
+	"[Install]".to_string(),
+	"WantedBy=multi-user.target".to_string(),
+	"".to_string(),
+    ].join("\n")
+}
+
+// User service: the user manager cannot order against system units, so it
+// polls for the vm-init unit before starting qemu.
+fn run_unit(confdir: &str, bindir: &path::Path, user: &str, name: &str) -> String {
+    let stop_timeout = env::var("VMSTOP_TIMEOUT").ok()
+	.and_then(|t| t.parse::<u64>().ok())
+	.unwrap_or(120) + 30;
+    [
+	"[Unit]".to_string(),
+	format!("Description=vmman VM {}", name),
+	"".to_string(),
+	"[Service]".to_string(),
+	"Type=simple".to_string(),
+	format!("Environment=VMCONF_DIR={}", confdir),
+	format!("ExecStartPre=/bin/sh -c 'until systemctl is-active -q {}; do sleep 1; done'",
+		init_unit_name(user, name)),
+	format!("ExecStart={} --supervise {}", bindir.join("vm-run").display(), name),
+	format!("ExecStop={} {}", bindir.join("vm-stop").display(), name),
+	"TimeoutStartSec=300".to_string(),
+	format!("TimeoutStopSec={}", stop_timeout),
+	"".to_string(),
+	"[Install]".to_string(),
+	"WantedBy=default.target".to_string(),
+	"".to_string(),
+    ].join("\n")
+}
+
+fn write_unit(dir: &path::Path, filename: &str, content: &str) {
+    fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Cannot create {}: {}", dir.display(), e));
+    let p = dir.join(filename);
+    fs::write(&p, content).unwrap_or_else(|e| panic!("Cannot write {}: {}", p.display(), e));
+    println!("  Wrote {}", p.display());
+}
+
+// vm-systemd [--output=DIR] [name...]
+//
+// Without names, generates units for every VM with `autostart = true`.
+pub fn generate(mgr: &mut vm::VmManager, args: &[String]) {
+    let user = vm::user_name();
+    let bindir = bin_dir(&args[0]);
+    let confdir = fs::canonicalize(&mgr.confdir).expect("Cannot resolve vm configuration dir");
+    let confdir = confdir.to_str().expect("Cannot decode vm configuration dir");
+    let names = args.iter().skip(1).filter(|a| !a.starts_with("--")).cloned().collect::<Vec<_>>();
+
+    let user_dir = vm::flag_value(args, "--output").map(path::PathBuf::from).unwrap_or_else(|| {
+	let config = env::var("XDG_CONFIG_HOME")
+	    .unwrap_or(env::var("HOME").expect("Cannot get home dir from the system!") + "/.config");
+	path::PathBuf::from(config).join("systemd/user")
+    });
+    let root_dir = vm::flag_value(args, "--output").map(path::PathBuf::from)
+	.unwrap_or(path::PathBuf::from(confdir).join("systemd"));
+
+    let mut generated = Vec::new();
+    for (name, conf) in mgr.vmconfs.iter_mut() {
+	if names.is_empty() {
+	    conf.load();
+	    if !conf.autostart {
+		continue;
+	    }
+	} else if !names.contains(name) {
+	    continue;
+	}
+	println!("Generating units for {}", name);
+	write_unit(&root_dir, &init_unit_name(&user, name), &init_unit(confdir, &bindir, &user, name));
+	write_unit(&user_dir, &format!("vmman-{}.service", name), &run_unit(confdir, &bindir, &user, name));
+	generated.push(name.clone());
+    }
+    for n in names.iter() {
+	if !generated.contains(n) {
+	    panic!("Cannot find machine {}", n);
+	}
+    }
+    if generated.is_empty() {
+	println!("No VM is marked with autostart = true");
+	return;
+    }
+
+    println!("Install the vm-init units as root and enable them:");
+    for n in generated.iter() {
+	println!("  # cp {} /etc/systemd/system/ && systemctl enable {}",
+		 root_dir.join(init_unit_name(&user, n)).display(), init_unit_name(&user, n));
+    }
+    println!("Then enable the user services and lingering so they start at boot:");
+    for n in generated.iter() {
+	println!("  $ systemctl --user enable vmman-{}.service", n);
+    }
+    println!("  $ loginctl enable-linger {}", user);
+}
@@ -0,0 +1,48 @@
+use crate::vm::{self, modules::storage::throttle_limit_name};
+
+fn usage() -> ! {
+    println!("Usage: vm-throttle <vm> <section|group> [limit=value ...]");
+    println!("       e.g. vm-throttle my-vm main iops=500 bps-write=52428800, 0 for no limit");
+    std::process::exit(1);
+}
+
+// A storage section with its own limits or in a group, or the name of a
+// [throttle.<group>] section.
+fn group_id(conf: &vm::VmConf, target: &str) -> String {
+    match conf.modules.iter().filter_map(|m| m.as_storage()).find(|s| s.name == target) {
+	Some(s) => s.throttle_group_id().unwrap_or_else(|| panic!("storage.{} has no I/O limits", target)),
+	None => format!("tg-{}", target),
+    }
+}
+
+// vm-throttle <vm> <section|group> [limit=value ...]
+//
+// Shows or changes the limits of a throttle group of the running VM. The
+// changes last until qemu exits.
+pub fn throttle(conf: &vm::VmConf, args: &[String]) {
+    let pos = vm::positional_args(args);
+    if pos.len() < 2 {
+	usage();
+    }
+    let group = group_id(conf, &pos[1]);
+    let mut qmp = conf.qmp();
+    if pos.len() == 2 {
+	let limits = qmp.execute("qom-get", json::object!{ "path": group.as_str(), "property": "limits" })
+	    .unwrap_or_else(|e| panic!("Cannot get the limits of {}: {}", group, e));
+	println!("{}:", group);
+	for (k, v) in limits.entries().filter(|(_, v)| v.as_u64().is_some_and(|n| n > 0)) {
+	    println!("  {} = {}", k, v);
+	}
+	return;
+    }
+
+    let mut limits = json::JsonValue::new_object();
+    for arg in pos[2..].iter() {
+	let (key, value) = arg.split_once('=').unwrap_or_else(|| usage());
+	let name = throttle_limit_name(key).unwrap_or_else(|| panic!("Unknown limit {}", key));
+	limits[name.as_str()] = value.parse::<u64>().unwrap_or_else(|e| panic!("Invalid value {}: {}", arg, e)).into();
+    }
+    qmp.execute("qom-set", json::object!{ "path": group.as_str(), "property": "limits", "value": limits })
+	.unwrap_or_else(|e| panic!("Cannot set the limits of {}: {}", group, e));
+    println!("Updated {}, edit the toml to keep the limits across restarts", group);
+}
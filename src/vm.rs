@@ -1,20 +1,35 @@
-use std::{env, fs};
+use std::{env, fs, path, process, thread::sleep, time};
 use toml::value;
-use std::os::unix::fs::MetadataExt;
-use std::collections::BTreeMap;
-use std::process::Command;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::collections::{BTreeMap, VecDeque};
+use std::process::{Child, Command};
 
-#[path = "modules.rs"] mod modules;
+#[path = "modules.rs"] pub mod modules;
+#[path = "qmp.rs"] pub mod qmp;
+#[path = "image.rs"] pub mod image;
+
+// Supervisor restart policy: the backoff doubles on every crash, and the
+// supervisor gives up after too many restarts within the window.
+const RESTART_BACKOFF_MIN: u64 = 1;
+const RESTART_BACKOFF_MAX: u64 = 64;
+const RESTART_STABLE_SECS: u64 = 60;
+const RESTART_WINDOW_SECS: u64 = 600;
+const RESTART_MAX_IN_WINDOW: usize = 5;
 
 pub struct VmConf {
     pub name: String,
     pub filename: String,
     uid: u32,
     gid: u32,
+    pub autostart: bool,
+    pub ephemeral: bool,
+    pub force_unlock: bool,
+    pub migrate_from: Option<(String, u32)>,
     pub modules: Vec<Box<dyn modules::ConfModule>>,
 }
 
 pub struct VmManager {
+    pub confdir: String,
     pub vmconfs: BTreeMap<String, VmConf>,
 }
 
@@ -25,10 +40,24 @@ impl VmConf {
 	    filename: String::from(filename),
 	    uid: 0,
 	    gid: 0,
+	    autostart: false,
+	    ephemeral: false,
+	    force_unlock: false,
+	    migrate_from: None,
 	    modules: Vec::<Box<dyn modules::ConfModule>>::new(),
 	}
     }
 
+    // Reads only the autostart key, without panicking on a broken file.
+    pub fn read_autostart(&self) -> Result<bool, String> {
+	let content = fs::read_to_string(&self.filename).map_err(|e| e.to_string())?;
+	let conf = toml::from_str::<value::Table>(&content).map_err(|e| e.to_string())?;
+	match conf.get("autostart") {
+	    Some(v) => v.as_bool().ok_or(String::from("Expecting autostart as a boolean")),
+	    None => Ok(false),
+	}
+    }
+
     pub fn load(self: &mut Self) {
 	let conf_file_content = fs::read_to_string(&self.filename).expect("Cannot read file");
 	let conf = toml::from_str::<value::Table>(&conf_file_content).expect("Cannot parse configuration");
@@ -36,10 +65,29 @@ impl VmConf {
 	self.uid = md.uid();
 	self.gid = md.gid();
 
+	let vmenv = self.vmenv();
+	let mut conf = conf;
+	self.record_formats(&mut conf, &vmenv);
+
 	for (module_name, sections) in conf {
-	    for (_, section) in sections.as_table().expect("Section must have names") {
-		self.modules.push(modules::create_module(&module_name, section.as_table().expect("Section must have names")));
+	    if module_name == "autostart" {
+		self.autostart = sections.as_bool().expect("Expecting autostart as a boolean");
+		continue;
 	    }
+	    for (section_name, section) in sections.as_table().expect("Section must have names") {
+		self.modules.push(modules::create_module(&module_name, section_name, section.as_table().expect("Section must have names"), &vmenv));
+	    }
+	}
+    }
+
+    pub fn vmenv(&self) -> modules::VmEnv {
+	modules::VmEnv {
+	    name: self.name.clone(),
+	    confdir: path::Path::new(&self.filename).parent().map(path::PathBuf::from).unwrap_or_default(),
+	    rundir: self.runtime_dir(),
+	    ephemeral: self.ephemeral,
+	    force_unlock: self.force_unlock,
+	    migrate_from: self.migrate_from.clone(),
 	}
     }
 
@@ -50,24 +98,376 @@ impl VmConf {
 	    m.init(self.uid, self.gid);
 	}
     }
-    pub fn run(self: &mut Self) {
-	let mut args = vec![String::from("-enable-kvm")];
+
+    // Gives back the resources handed over by init().
+    pub fn deinit(&mut self) {
+	for m in self.modules.iter() {
+	    m.deinit(self.uid, self.gid);
+	}
+    }
+
+    // A single section of the toml, e.g. for hot-plugging it into a
+    // running VM.
+    pub fn section(&self, heading: &str, name: &str) -> Option<Box<dyn modules::ConfModule>> {
+	let content = fs::read_to_string(&self.filename).expect("Cannot read file");
+	let conf = toml::from_str::<value::Table>(&content).expect("Cannot parse configuration");
+	conf.get(heading)
+	    .and_then(|s| s.get(name))
+	    .and_then(|s| s.as_table())
+	    .map(|s| modules::create_module(heading, name, s, &self.vmenv()))
+    }
+
+    // vm-init --section=heading.name, with the ownership of the toml.
+    pub fn init_section(&self, section: &str, deinit: bool) {
+	let (heading, name) = section.split_once('.').expect("Expecting the section as heading.name");
+	let m = self.section(heading, name).unwrap_or_else(|| panic!("Cannot find [{}] in {}", section, self.filename));
+	if deinit {
+	    m.deinit(self.uid, self.gid);
+	} else {
+	    m.init(self.uid, self.gid);
+	}
+    }
+
+    // The guest writes the header of a raw image, so the format is only
+    // probed once and recorded in the toml. Otherwise a qcow2 header written
+    // by the guest would open a host file as backing on the next start.
+    fn record_formats(&self, conf: &mut value::Table, vmenv: &modules::VmEnv) {
+	let sections = match conf.get_mut("storage").and_then(|s| s.as_table_mut()) {
+	    Some(s) => s,
+	    None => return,
+	};
+	for (name, section) in sections.iter_mut() {
+	    let section = match section.as_table_mut() {
+		Some(s) if !s.contains_key("format") => s,
+		_ => continue,
+	    };
+	    let storage = modules::storage::StorageModule::new(name, section, vmenv);
+	    if storage.is_network() || storage.is_block_device() || !path::Path::new(&storage.filename).exists() {
+		continue;
+	    }
+	    let format = storage.image_format();
+	    self.set_section_key("storage", name, "format", &format).unwrap_or_else(|e| {
+		panic!("storage.{}: {}, set format = \"{}\" in {}", name, e, format, self.filename)
+	    });
+	    println!("storage.{}: recorded format = \"{}\" in {}", name, format, self.filename);
+	    section.insert(String::from("format"), value::Value::String(format));
+	}
+    }
+
+    // Adds a string key right below the [heading.name] line, keeping the
+    // rest of the file as written.
+    pub fn set_section_key(&self, heading: &str, name: &str, key: &str, v: &str) -> Result<(), String> {
+	let content = fs::read_to_string(&self.filename).map_err(|e| e.to_string())?;
+	let idx = content.lines().position(|l| table_header(l) == Some(vec![String::from(heading), String::from(name)]))
+	    .ok_or(format!("Cannot find [{}.{}]", heading, name))?;
+	let line = format!("{} = {}", key, value::Value::String(String::from(v)));
+	let mut lines = content.lines().collect::<Vec<_>>();
+	lines.insert(idx + 1, &line);
+	let updated = lines.iter().map(|l| format!("{}\n", l)).collect::<String>();
+	let conf = toml::from_str::<value::Table>(&updated).map_err(|e| e.to_string())?;
+	if conf.get(heading).and_then(|s| s.get(name)).and_then(|s| s.get(key)).and_then(|k| k.as_str()) != Some(v) {
+	    return Err(format!("Cannot add {} to [{}.{}]", key, heading, name));
+	}
+	fs::write(&self.filename, updated).map_err(|e| e.to_string())
+    }
+
+    // Drops the [heading.name] section. The lines of the section are
+    // removed when that gives the same configuration, otherwise the whole
+    // file is rewritten from the parsed toml.
+    pub fn remove_section(&self, heading: &str, name: &str) -> Result<(), String> {
+	let content = fs::read_to_string(&self.filename).map_err(|e| e.to_string())?;
+	let mut conf = toml::from_str::<value::Table>(&content).map_err(|e| e.to_string())?;
+	let sections = conf.get_mut(heading).and_then(|s| s.as_table_mut());
+	if sections.and_then(|s| s.remove(name)).is_none() {
+	    return Err(format!("Cannot find [{}.{}] in {}", heading, name, self.filename));
+	}
+	if conf.get(heading).and_then(|s| s.as_table()).is_some_and(|s| s.is_empty()) {
+	    conf.remove(heading);
+	}
+	let mut skipping = false;
+	let kept = content.lines()
+	    .filter(|l| {
+		if let Some(keys) = table_header(l) {
+		    skipping = keys == [heading, name];
+		}
+		!skipping
+	    })
+	    .map(|l| format!("{}\n", l))
+	    .collect::<String>();
+	let updated = if toml::from_str::<value::Table>(&kept).ok().as_ref() == Some(&conf) {
+	    kept
+	} else {
+	    toml::to_string(&value::Value::Table(conf)).map_err(|e| e.to_string())?
+	};
+	fs::write(&self.filename, updated).map_err(|e| e.to_string())
+    }
+
+    // Per-VM directory for sockets and other runtime state of the user.
+    pub fn runtime_dir(&self) -> path::PathBuf {
+	let base = env::var("VMMAN_RUNTIME_DIR")
+	    .or_else(|_| env::var("XDG_RUNTIME_DIR").map(|d| d + "/vmman"))
+	    .unwrap_or(format!("/tmp/vmman-{}", self.uid));
+	let mut p = path::PathBuf::from(base);
+	p.push(&self.name);
+	p
+    }
+
+    pub fn qmp_socket(&self) -> path::PathBuf {
+	self.runtime_dir().join("qmp.sock")
+    }
+
+    // A second QMP socket for vm-monitor, so that an open interactive
+    // monitor does not block the other commands.
+    pub fn monitor_socket(&self) -> path::PathBuf {
+	self.runtime_dir().join("monitor.sock")
+    }
+
+    pub fn console_socket(&self) -> path::PathBuf {
+	self.runtime_dir().join(modules::CONSOLE_SOCKET)
+    }
+
+    fn pid_file(&self) -> path::PathBuf {
+	self.runtime_dir().join("qemu.pid")
+    }
+
+    // The pid of the running qemu, as recorded when it was spawned.
+    pub fn qemu_pid(&self) -> Option<u32> {
+	fs::read_to_string(self.pid_file()).ok().and_then(|p| p.trim().parse::<u32>().ok())
+    }
+
+    fn stop_marker(&self) -> path::PathBuf {
+	self.runtime_dir().join("stopped")
+    }
+
+    fn spawn(&mut self, extra_args: &[String]) -> Child {
+	let rundir = self.runtime_dir();
+	fs::DirBuilder::new().recursive(true).mode(0o700).create(&rundir)
+	    .unwrap_or_else(|e| panic!("Cannot create runtime dir {}: {}", rundir.display(), e));
+
+	let mut args = vec![String::from("-enable-kvm"),
+			    String::from("-name"), self.name.clone(),
+			    String::from("-qmp"), qmp_arg(&self.qmp_socket()),
+			    String::from("-qmp"), qmp_arg(&self.monitor_socket())];
 	for m in self.modules.iter_mut() {
 	    args.extend(m.startup_args());
 	}
+	args.extend_from_slice(extra_args);
 	for a in &args {
 	    print!("{} ", &a);
 	}
-	println!("");
+	println!();
 
 	let qemubin = env::var("QEMU_BIN").unwrap_or(String::from("/usr/bin/qemu-system-x86_64"));
-	
+
 	let p = Command::new(&qemubin)
 	    .args(args)
 	    .spawn().expect("Cannot spawn qemu process");
 	println!("Qemu started with {}", p.id());
+	let _ = fs::write(self.pid_file(), p.id().to_string());
 	for m in self.modules.iter_mut() {
-	    m.post_startup();
+	    m.post_startup(p.id());
+	}
+	p
+    }
+
+    pub fn run(&mut self) {
+	let mut p = self.spawn(&[]);
+	if self.ephemeral {
+	    self.wait(&mut p);
+	} else {
+	    self.wait_qmp(&mut p);
+	}
+    }
+
+    // Waits for qemu to exit and lets the modules clean up after it. Ctrl-C
+    // reaches qemu as well, so only qemu handles it.
+    fn wait(&mut self, p: &mut Child) -> process::ExitStatus {
+	unsafe { libc::signal(libc::SIGINT, libc::SIG_IGN) };
+	let status = p.wait().expect("Cannot wait for qemu process");
+	unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+	for m in self.modules.iter_mut() {
+	    m.cleanup();
+	}
+	status
+    }
+
+    // Starts qemu waiting for an incoming migration on `uri`.
+    pub fn run_incoming(&mut self, uri: &str) {
+	let mut p = self.spawn(&[String::from("-incoming"), String::from(uri)]);
+	self.wait_qmp(&mut p);
+    }
+
+    // Stays attached to qemu and restarts it when it exits abnormally, until
+    // vm-stop marks the VM as stopped.
+    pub fn supervise(&mut self) {
+	let _ = fs::remove_file(self.stop_marker());
+	let mut restarts = VecDeque::<time::Instant>::new();
+	let mut backoff = RESTART_BACKOFF_MIN;
+	loop {
+	    let started = time::Instant::now();
+	    let mut p = self.spawn(&[]);
+	    let status = self.wait(&mut p);
+	    if self.stop_marker().exists() {
+		println!("{} stopped by vm-stop", self.name);
+		break;
+	    }
+	    if status.success() {
+		println!("{} exited normally", self.name);
+		break;
+	    }
+
+	    let now = time::Instant::now();
+	    if now.duration_since(started).as_secs() >= RESTART_STABLE_SECS {
+		backoff = RESTART_BACKOFF_MIN;
+	    }
+	    while restarts.front().is_some_and(|t| now.duration_since(*t).as_secs() >= RESTART_WINDOW_SECS) {
+		restarts.pop_front();
+	    }
+	    if restarts.len() >= RESTART_MAX_IN_WINDOW {
+		println!("{} crashed {} times within {}s, giving up",
+			 self.name, restarts.len() + 1, RESTART_WINDOW_SECS);
+		process::exit(1);
+	    }
+
+	    println!("Qemu exited with {}, restarting {} in {}s", status, self.name, backoff);
+	    sleep(time::Duration::from_secs(backoff));
+	    if self.stop_marker().exists() {
+		println!("{} stopped by vm-stop", self.name);
+		break;
+	    }
+	    restarts.push_back(time::Instant::now());
+	    backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+	}
+	let _ = fs::remove_file(self.stop_marker());
+    }
+
+    pub fn qmp(&self) -> qmp::Qmp {
+	qmp::Qmp::connect(self.qmp_socket()).unwrap_or_else(|e| panic!("Cannot connect to the monitor of {}: {}", self.name, e))
+    }
+
+    // The monitor of the VM if it is running.
+    pub fn try_qmp(&self) -> Option<qmp::Qmp> {
+	qmp::Qmp::connect(self.qmp_socket()).ok()
+    }
+
+    pub fn storage(&self, section: &str) -> &modules::storage::StorageModule {
+	self.modules.iter()
+	    .filter_map(|m| m.as_storage())
+	    .find(|s| s.name == section)
+	    .unwrap_or_else(|| panic!("Cannot find storage.{} in {}", section, self.name))
+    }
+
+    // qemu creates the QMP socket shortly after it starts.
+    // Waits for the monitor of the qemu just spawned as `p`. qemu is left
+    // running once it answers, but is reaped if it exits before that.
+    fn wait_qmp(&mut self, p: &mut Child) -> qmp::Qmp {
+	for _ in 0..100 {
+	    if let Ok(q) = qmp::Qmp::connect(self.qmp_socket()) {
+		return q;
+	    }
+	    if let Some(status) = p.try_wait().expect("Cannot wait for qemu process") {
+		for m in self.modules.iter_mut() {
+		    m.cleanup();
+		}
+		panic!("Qemu exited before {} started: {}", self.name, status);
+	    }
+	    sleep(time::Duration::from_millis(100));
+	}
+	self.qmp()
+    }
+
+    pub fn pause(&self) {
+	self.qmp().execute("stop", json::JsonValue::new_object()).expect("Cannot pause the VM");
+	println!("{} paused", self.name);
+    }
+
+    pub fn resume(&self) {
+	self.qmp().execute("cont", json::JsonValue::new_object()).expect("Cannot resume the VM");
+	println!("{} resumed", self.name);
+    }
+
+    pub fn reset(&self) {
+	self.qmp().execute("system_reset", json::JsonValue::new_object()).expect("Cannot reset the VM");
+	println!("{} reset", self.name);
+    }
+
+    // Migrates the paused VM into a file and quits.
+    pub fn save(&self, file: &str) {
+	let file = absolute_path(file);
+	let mut qmp = self.qmp();
+	qmp.execute("stop", json::JsonValue::new_object()).expect("Cannot pause the VM");
+	println!("Saving {} to {}", self.name, file.display());
+	let uri = format!("exec:cat > {}", shell_quote(file.to_str().expect("Cannot decode filename")));
+	qmp.execute("migrate", json::object!{ "uri": uri }).expect("Cannot start migration");
+	if let Err(e) = wait_migration(&mut qmp) {
+	    let _ = qmp.execute("cont", json::JsonValue::new_object());
+	    panic!("Cannot save {}: {}", self.name, e);
+	}
+	qmp.execute("quit", json::JsonValue::new_object()).expect("Cannot quit qemu");
+	// Like stop(), so that the VM can be restored on another host.
+	for s in self.modules.iter().filter_map(|m| m.as_storage()) {
+	    s.release_lease();
+	}
+	println!("{} saved", self.name);
+    }
+
+    // Starts qemu with the same command line, waiting for the state saved by
+    // save(), and resumes the VM once it is loaded.
+    pub fn restore(&mut self, file: &str) {
+	let file = absolute_path(file);
+	if !file.exists() {
+	    panic!("Cannot find saved state {}", file.display());
+	}
+	let incoming = format!("exec:cat {}", shell_quote(file.to_str().expect("Cannot decode filename")));
+	let mut p = self.spawn(&[String::from("-incoming"), incoming]);
+	let mut qmp = self.wait_qmp(&mut p);
+	println!("Restoring {} from {}", self.name, file.display());
+	loop {
+	    let status = qmp.execute("query-status", json::JsonValue::new_object()).expect("Cannot query VM status");
+	    if status["status"] != "inmigrate" {
+		break;
+	    }
+	    sleep(time::Duration::from_millis(500));
+	}
+	qmp.execute("cont", json::JsonValue::new_object()).expect("Cannot resume the VM");
+	println!("{} restored", self.name);
+    }
+
+    // Asks the guest to power down and waits for qemu to go away, falling
+    // back to quit after the timeout or immediately with `force`. The leases
+    // of the disks are given back, so that other hosts can run the VM.
+    pub fn stop(&self, force: bool) {
+	self.shutdown(force);
+	for s in self.modules.iter().filter_map(|m| m.as_storage()) {
+	    s.release_lease();
+	}
+    }
+
+    fn shutdown(&self, force: bool) {
+	let _ = fs::write(self.stop_marker(), b"");
+	let mut qmp = match qmp::Qmp::connect(self.qmp_socket()) {
+	    Ok(q) => q,
+	    Err(e) => {
+		println!("{} is not running ({})", self.name, e);
+		return;
+	    }
+	};
+	if !force {
+	    let timeout = env::var("VMSTOP_TIMEOUT").ok()
+		.and_then(|t| t.parse::<u64>().ok())
+		.unwrap_or(120);
+	    qmp.execute("system_powerdown", json::JsonValue::new_object()).expect("Cannot power down the VM");
+	    println!("Waiting up to {}s for {} to shut down", timeout, self.name);
+	    match qmp.wait_event("SHUTDOWN", time::Duration::from_secs(timeout)) {
+		Ok(_) => {
+		    println!("{} shut down", self.name);
+		    return;
+		}
+		Err(e) => println!("{}, forcing quit", e),
+	    }
+	}
+	if let Err(e) = qmp.execute("quit", json::JsonValue::new_object()) {
+	    println!("{}", e);
 	}
     }
 }
@@ -76,7 +476,11 @@ impl VmManager {
     pub fn new() -> VmManager {
 	let confdir = env::var("VMCONF_DIR")
 	    .unwrap_or(env::var("HOME").expect("Cannot get home dir from the system!") + "/vm");
-	let dir = fs::read_dir(&confdir).expect(&format!("Cannot open vm configuration dir {}", &confdir));
+	VmManager::from_dir(&confdir)
+    }
+
+    pub fn from_dir(confdir: &str) -> VmManager {
+	let dir = fs::read_dir(confdir).expect(&format!("Cannot open vm configuration dir {}", &confdir));
 	let err = "Cannot decode filename";
 
 	let vmconfs =
@@ -92,8 +496,11 @@ impl VmManager {
 				return None;
 			    }
 			    
-			    if entry.path().extension().expect(err).to_str() == Some("toml") {
-				return Some(entry.path());
+			    // Other files, e.g. a README, and names that are not
+			    // UTF-8 are not VMs.
+			    let p = entry.path();
+			    if p.extension().and_then(|e| e.to_str()) == Some("toml") && p.to_str().is_some() {
+				return Some(p);
 			    } else {
 				return None;
 			    }
@@ -110,14 +517,105 @@ impl VmManager {
 	    ).collect::<BTreeMap<_, _>>();
 
 	VmManager {
+	    confdir: String::from(confdir),
 	    vmconfs: vmconfs,
 	}
     }
 }
 
+// The keys of a [table] header line of a toml file, e.g. storage and main
+// for [storage.main], with an empty path for [[array]] headers.
+fn table_header(line: &str) -> Option<Vec<String>> {
+    if !line.trim_start().starts_with('[') {
+	return None;
+    }
+    let mut v = value::Value::Table(toml::from_str::<value::Table>(line).ok()?);
+    let mut keys = Vec::new();
+    while let Some((k, sub)) = v.as_table().and_then(|t| t.iter().next()).map(|(k, s)| (k.clone(), s.clone())) {
+	keys.push(k);
+	v = sub;
+    }
+    Some(if v.is_array() { Vec::new() } else { keys })
+}
+
+// A QMP server socket, with the path escaped like other qemu options.
+fn qmp_arg(socket: &path::Path) -> String {
+    let props = modules::storage::Props::new()
+	.str("path", socket.to_str().expect("Cannot decode filename"))
+	.bool("server", true)
+	.bool("wait", false);
+    format!("unix:{}", props.to_arg_implied("path"))
+}
+
+pub fn has_flag(args: &[String], flag: &str) -> bool {
+    args.iter().skip(1).any(|a| a == flag)
+}
+
+pub fn user_name() -> String {
+    let uid = unsafe { libc::getuid() };
+    let pw = unsafe { libc::getpwuid(uid) };
+    if pw.is_null() {
+	return format!("{}", uid);
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr((*pw).pw_name) };
+    String::from(name.to_str().expect("Cannot decode user name"))
+}
+
+fn absolute_path(p: &str) -> path::PathBuf {
+    env::current_dir().expect("Cannot get current dir").join(p)
+}
+
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// Polls query-migrate until the migration finishes, printing the progress.
+pub fn wait_migration(qmp: &mut qmp::Qmp) -> Result<(), String> {
+    loop {
+	let r = qmp.execute("query-migrate", json::JsonValue::new_object())?;
+	let status = r["status"].as_str().unwrap_or("none");
+	let total = r["ram"]["total"].as_u64().unwrap_or(0);
+	let transferred = r["ram"]["transferred"].as_u64().unwrap_or(0);
+	if total > 0 {
+	    print!("Migration {}: {:6}M/{:6}M\r", status, transferred / 1024 / 1024, total / 1024 / 1024);
+	    std::io::Write::flush(&mut std::io::stdout()).unwrap();
+	}
+	match status {
+	    "completed" => {
+		println!();
+		return Ok(());
+	    }
+	    "failed" | "cancelled" => {
+		println!();
+		return Err(format!("migration {}: {}", status, r["error-desc"].as_str().unwrap_or("unknown error")));
+	    }
+	    _ => sleep(time::Duration::from_millis(500)),
+	}
+    }
+}
+
+// Arguments that are not --flags, the first one being the machine name.
+pub fn positional_args(args: &[String]) -> Vec<String> {
+    args.iter().skip(1).filter(|a| !a.starts_with("--")).cloned().collect()
+}
+
+// Value of a `--flag=value` argument.
+pub fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    let prefix = format!("{}=", flag);
+    args.iter().skip(1).find(|a| a.starts_with(&prefix)).map(|a| String::from(&a[prefix.len()..]))
+}
+
 pub fn load_vm<'a>(mgr: &'a mut VmManager, args: &Vec<String>) -> &'a mut VmConf {
-    let target: &str = args.get(1).expect("Expecting a machine name");
+    let target: &str = args.iter().skip(1).find(|a| !a.starts_with("--")).expect("Expecting a machine name");
     let conf = mgr.vmconfs.get_mut(target).expect(&format!("Cannot find machine {}", target));
+    conf.ephemeral = has_flag(args, "--ephemeral");
+    conf.force_unlock = has_flag(args, "--force-unlock");
+    // The source of an incoming migration, as host:pid of its qemu, holds
+    // the leases until it hands the VM over.
+    conf.migrate_from = flag_value(args, "--migrate-from").map(|f| {
+	let (host, pid) = f.rsplit_once(':').expect("Expecting --migrate-from=HOST:PID");
+	(String::from(host), pid.parse::<u32>().expect("Invalid pid in --migrate-from"))
+    });
     conf.load();
     return conf;
 }